@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Handle acknowledging a [`Notification`](crate::Notification) received with
+/// [`Client::with_manual_ack()`](crate::Client::with_manual_ack).
+///
+/// Notifications that are never acknowledged are redelivered by FCM after a reconnect.
+#[derive(Debug, Clone)]
+pub struct AckHandle {
+    persistent_id: String,
+    sender: UnboundedSender<String>,
+}
+
+impl AckHandle {
+    /// Returns the persistent ID of the notification.
+    pub fn persistent_id(&self) -> &str {
+        &self.persistent_id
+    }
+
+    /// Acknowledges the notification.
+    ///
+    /// Has no effect once the [`Client`](crate::Client) has been dropped.
+    pub fn ack(&self) {
+        let _ = self.sender.send(self.persistent_id.clone());
+    }
+}
+
+impl PartialEq for AckHandle {
+    fn eq(&self, other: &Self) -> bool {
+        self.persistent_id == other.persistent_id && self.sender.same_channel(&other.sender)
+    }
+}
+
+impl Eq for AckHandle {}
+
+/// Persistent IDs delivered with an [`AckHandle`] and not acknowledged yet.
+#[derive(Debug)]
+pub(crate) struct Acks {
+    sender: UnboundedSender<String>,
+    receiver: UnboundedReceiver<String>,
+    pending: HashSet<String>,
+}
+
+impl Acks {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver,
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Returns a handle for a delivered persistent ID.
+    pub(crate) fn handle(&mut self, persistent_id: String) -> AckHandle {
+        self.pending.insert(persistent_id.clone());
+        AckHandle {
+            persistent_id,
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Waits for acknowledged persistent IDs, returning all that are available.
+    pub(crate) async fn recv(&mut self) -> Vec<String> {
+        // The channel never closes, `self` holds a sender.
+        let Some(persistent_id) = self.receiver.recv().await else {
+            return std::future::pending().await;
+        };
+        let mut persistent_ids = vec![persistent_id];
+        while let Ok(persistent_id) = self.receiver.try_recv() {
+            persistent_ids.push(persistent_id);
+        }
+        persistent_ids.sort_unstable();
+        persistent_ids.dedup();
+        for persistent_id in &persistent_ids {
+            self.pending.remove(persistent_id);
+        }
+        persistent_ids
+    }
+
+    /// Returns and forgets pending IDs, FCM redelivers them on the new connection.
+    pub(crate) fn take_pending(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.pending)
+    }
+}
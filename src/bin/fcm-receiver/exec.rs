@@ -0,0 +1,116 @@
+//! Runs a command for each notification.
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use fcm_receiver::Notification;
+use tokio::io::AsyncWriteExt as _;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+/// Runs `command` with `sh -c` for notifications, at most `concurrency` at a time.
+#[derive(Debug, Clone)]
+pub struct Exec {
+    command: Arc<str>,
+    timeout: Duration,
+    permits: Arc<Semaphore>,
+}
+
+impl Exec {
+    pub fn new(command: String, concurrency: usize, timeout: Duration) -> Self {
+        Self {
+            command: command.into(),
+            timeout,
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+        }
+    }
+
+    /// Runs the command in the background, acknowledging the notification if it succeeds.
+    ///
+    /// The command starts once fewer than `concurrency` commands are running. Waiting happens in
+    /// the background, so the caller keeps reading notifications and the connection stays alive.
+    pub fn spawn(&self, notification: Notification) {
+        let exec = self.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = exec.permits.acquire().await else {
+                return;
+            };
+            let persistent_id = &notification.persistent_id;
+            match time::timeout(exec.timeout, exec.run(&notification)).await {
+                Ok(Ok(status)) if status.success() => {
+                    if let Some(ack) = notification.ack {
+                        ack.ack();
+                    }
+                }
+                Ok(Ok(status)) => log::warn!("command for {persistent_id} failed: {status}"),
+                Ok(Err(error)) => log::error!("failed to run command for {persistent_id}: {error}"),
+                Err(_) => log::warn!(
+                    "command for {persistent_id} timed out after {} s",
+                    exec.timeout.as_secs()
+                ),
+            }
+        });
+    }
+
+    async fn run(&self, notification: &Notification) -> std::io::Result<std::process::ExitStatus> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&*self.command)
+            .envs(env(notification))
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // The command may exit without reading its input.
+        if let Err(error) = stdin.write_all(&notification.payload).await {
+            log::debug!("failed to write payload to command: {error}");
+        }
+        drop(stdin);
+
+        child.wait().await
+    }
+}
+
+/// Returns the environment variables describing a notification.
+///
+/// App-data entries are exported as `FCM_DATA_<KEY>`, with the key upper-cased and characters
+/// other than ASCII letters and digits replaced by `_`.
+fn env(notification: &Notification) -> Vec<(String, String)> {
+    let mut env = vec![
+        (
+            "FCM_PERSISTENT_ID".into(),
+            notification.persistent_id.clone(),
+        ),
+        ("FCM_FROM".into(), notification.from.clone()),
+        ("FCM_CATEGORY".into(), notification.category.clone()),
+        ("FCM_APP_ID".into(), notification.app_id.clone()),
+        (
+            "FCM_SENT".into(),
+            notification
+                .sent
+                .map(|sent| sent.to_string())
+                .unwrap_or_default(),
+        ),
+        (
+            "FCM_TTL".into(),
+            notification
+                .ttl
+                .map(|ttl| ttl.to_string())
+                .unwrap_or_default(),
+        ),
+        ("FCM_ENCRYPTED".into(), notification.encrypted.to_string()),
+    ];
+    for (key, value) in &notification.data {
+        let key: String = key
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c.to_ascii_uppercase(),
+                false => '_',
+            })
+            .collect();
+        env.push((format!("FCM_DATA_{key}"), value.clone()));
+    }
+    env
+}
@@ -0,0 +1,384 @@
+//! Command-line interface for registering with FCM and receiving notifications.
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use clap::{Parser, Subcommand};
+#[cfg(unix)]
+use fcm_receiver::fanout::FanOut;
+use fcm_receiver::webhook::Webhook;
+use fcm_receiver::{Client, Credentials, Error, Notification, StorageError};
+use futures_util::StreamExt as _;
+use serde::Serialize;
+use serde_json::Value;
+
+use exec::Exec;
+
+mod exec;
+
+/// Credential fields replaced by `show-credentials`.
+const SECRET_FIELDS: &[&str] = &[
+    "/keys/privateKey",
+    "/keys/authSecret",
+    "/gcm/token",
+    "/gcm/securityToken",
+    "/fcm/token",
+];
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Credentials file.
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "FCM_RECEIVER_CREDENTIALS",
+        default_value = "credentials.json"
+    )]
+    credentials: PathBuf,
+    /// Passphrase of encrypted credentials, new credentials are encrypted if set.
+    #[arg(
+        long,
+        global = true,
+        env = "FCM_RECEIVER_PASSPHRASE",
+        hide_env_values = true
+    )]
+    passphrase: Option<String>,
+    /// Probes for the longest heartbeat interval the network tolerates, remembering it in the
+    /// credentials file.
+    #[arg(long, global = true)]
+    adaptive_heartbeat: bool,
+    /// Address serving Prometheus metrics of the client over HTTP.
+    #[cfg(feature = "metrics")]
+    #[arg(long, global = true, value_name = "ADDR")]
+    metrics: Option<std::net::SocketAddr>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Registers with FCM and writes the credentials file.
+    Register {
+        /// Sender ID of the Firebase project.
+        #[arg(long)]
+        sender_id: String,
+        /// Overwrites an existing credentials file.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Receives notifications and writes them to stdout as newline delimited JSON.
+    Listen {
+        /// Runs a shell command for each notification instead, with the payload on stdin and
+        /// metadata in `FCM_*` environment variables.
+        ///
+        /// Notifications are only acknowledged once the command exits successfully.
+        #[arg(long, value_name = "COMMAND")]
+        exec: Option<String>,
+        /// Maximum number of commands running at once.
+        #[arg(long, default_value_t = 1, requires = "exec")]
+        concurrency: usize,
+        /// Seconds after which a command is killed.
+        #[arg(long, default_value_t = 60, requires = "exec")]
+        timeout: u64,
+    },
+    /// Receives notifications and POSTs them to a webhook.
+    ///
+    /// Notifications are only acknowledged once the webhook responds with a 2xx status.
+    Forward {
+        /// URL of the webhook.
+        #[arg(long)]
+        url: String,
+        /// Secret for signing requests with HMAC-SHA256 in the `X-Fcm-Signature` header.
+        #[arg(long, env = "FCM_RECEIVER_WEBHOOK_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+        /// Attempts before a delivery is queued or dropped.
+        #[arg(long, default_value_t = 5)]
+        attempts: u32,
+        /// Directory persisting failed deliveries until they succeed.
+        #[arg(long)]
+        queue: Option<PathBuf>,
+        /// Maximum number of deliveries in flight at once.
+        #[arg(long, default_value_t = 16)]
+        concurrency: usize,
+        /// Seconds after which a request is aborted and retried.
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+    },
+    /// Receives notifications and publishes them to subscribers on a Unix domain socket.
+    #[cfg(unix)]
+    Serve {
+        /// Path of the socket.
+        #[arg(long)]
+        socket: PathBuf,
+        /// Number of recent notifications sent to new subscribers.
+        #[arg(long, default_value_t = 0)]
+        replay: usize,
+    },
+    /// Checks in with GCM and logs in to MCS without receiving notifications.
+    CheckIn,
+    /// Prints the credentials with secrets redacted.
+    ShowCredentials,
+}
+
+/// Notification as written by `listen`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NotificationLine<'a> {
+    persistent_id: &'a str,
+    from: &'a str,
+    category: &'a str,
+    app_id: &'a str,
+    sent: Option<i64>,
+    ttl: Option<i32>,
+    encrypted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trusted: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vapid_key: Option<&'a str>,
+    data: &'a HashMap<String, String>,
+    /// Payload as JSON if it parses, as a string if it is UTF-8.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<Value>,
+    /// Base64 encoded payload if it is not UTF-8.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_base64: Option<String>,
+}
+
+impl<'a> NotificationLine<'a> {
+    fn new(notification: &'a Notification) -> Self {
+        let (payload, payload_base64) = match std::str::from_utf8(&notification.payload) {
+            Ok("") => (None, None),
+            Ok(text) => (
+                Some(serde_json::from_str(text).unwrap_or_else(|_| Value::from(text))),
+                None,
+            ),
+            Err(_) => (
+                None,
+                Some(BASE64_URL_SAFE_NO_PAD.encode(&notification.payload)),
+            ),
+        };
+
+        Self {
+            persistent_id: &notification.persistent_id,
+            from: &notification.from,
+            category: &notification.category,
+            app_id: &notification.app_id,
+            sent: notification.sent,
+            ttl: notification.ttl,
+            encrypted: notification.encrypted,
+            trusted: notification.trusted,
+            vapid_key: notification.vapid_key.as_deref(),
+            data: &notification.data,
+            payload,
+            payload_base64,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let cli = Cli::parse();
+
+    match run(cli).await {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<ExitCode, Error> {
+    #[cfg(feature = "metrics")]
+    let metrics = match cli.metrics {
+        Some(address) => {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            let metrics = fcm_receiver::metrics::Metrics::new();
+            tokio::spawn(metrics.clone().serve(listener));
+            Some(metrics)
+        }
+        None => None,
+    };
+    let new_client = || -> Result<Client, Error> {
+        let credentials = load_credentials(&cli.credentials, cli.passphrase.as_deref())?;
+        let mut client = Client::new(credentials)?;
+        if cli.adaptive_heartbeat {
+            let state = Credentials::load_heartbeat_state(&cli.credentials)?.unwrap_or_default();
+            let path = cli.credentials.clone();
+            client = client
+                .with_adaptive_heartbeat(state)
+                .with_heartbeat_state_handler(move |state| {
+                    if let Err(error) = Credentials::save_heartbeat_state(&path, state) {
+                        log::warn!("failed to save heartbeat state: {error}");
+                    }
+                });
+        }
+        #[cfg(feature = "metrics")]
+        let client = match &metrics {
+            Some(metrics) => client.with_metrics(metrics.clone()),
+            None => client,
+        };
+        Ok(client)
+    };
+
+    match cli.command {
+        Command::Register { sender_id, force } => {
+            if !force && cli.credentials.exists() {
+                return Err(format!(
+                    "{} already exists, use --force to overwrite it",
+                    cli.credentials.display()
+                )
+                .into());
+            }
+            let credentials = Client::register(sender_id).await?;
+            match &cli.passphrase {
+                Some(passphrase) => credentials.save_encrypted(&cli.credentials, passphrase)?,
+                None => credentials.save(&cli.credentials)?,
+            }
+            eprintln!("credentials written to {}", cli.credentials.display());
+        }
+        Command::Listen {
+            exec,
+            concurrency,
+            timeout,
+        } => {
+            let client = new_client()?;
+            match exec {
+                Some(command) => {
+                    let exec = Exec::new(command, concurrency, Duration::from_secs(timeout));
+                    listen(client.with_manual_ack(), |notification| {
+                        exec.spawn(notification);
+                        std::future::ready(Ok(()))
+                    })
+                    .await?;
+                }
+                None => {
+                    let mut stdout = io::stdout().lock();
+                    listen(client, |notification| {
+                        std::future::ready(write_line(&mut stdout, &notification))
+                    })
+                    .await?;
+                }
+            }
+        }
+        Command::Forward {
+            url,
+            secret,
+            attempts,
+            queue,
+            concurrency,
+            timeout,
+        } => {
+            let client = new_client()?;
+            let mut webhook = Webhook::new(url)
+                .with_retries(attempts, Duration::from_secs(1), Duration::from_secs(60))
+                .with_concurrency(concurrency)
+                .with_timeout(Duration::from_secs(timeout));
+            if let Some(secret) = secret {
+                webhook = webhook.with_secret(secret);
+            }
+            if let Some(queue) = queue {
+                webhook = webhook.with_queue(queue);
+            }
+            tokio::select! {
+                () = webhook.run(client) => (),
+                _ = tokio::signal::ctrl_c() => (),
+            }
+        }
+        #[cfg(unix)]
+        Command::Serve { socket, replay } => {
+            let client = new_client()?;
+            let fan_out = FanOut::new(&socket).with_replay(replay);
+            tokio::select! {
+                result = fan_out.run(client) => result?,
+                _ = tokio::signal::ctrl_c() => (),
+            }
+            let _ = std::fs::remove_file(socket);
+        }
+        Command::CheckIn => {
+            let report = new_client()?.verify().await;
+            match &report.check_in {
+                Ok(duration) => println!("check-in: ok ({} ms)", duration.as_millis()),
+                Err(error) => println!("check-in: failed: {error}"),
+            }
+            match &report.login {
+                Some(Ok(login)) => println!("login: ok ({} ms)", login.duration.as_millis()),
+                Some(Err(error)) => println!("login: failed: {error}"),
+                None => (),
+            }
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::ShowCredentials => {
+            let credentials = load_credentials(&cli.credentials, cli.passphrase.as_deref())?;
+            let mut value = serde_json::to_value(&credentials)?;
+            for pointer in SECRET_FIELDS {
+                if let Some(field) = value.pointer_mut(pointer) {
+                    *field = "[redacted]".into();
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&value)?);
+            if let Err(error) = credentials.validate() {
+                eprintln!("{error}");
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn load_credentials(path: &Path, passphrase: Option<&str>) -> Result<Credentials, Error> {
+    let result = match passphrase {
+        Some(passphrase) => Credentials::load_encrypted(path, passphrase),
+        None => Credentials::load(path),
+    };
+    match result {
+        Err(StorageError::Encrypted) => {
+            Err("credentials are encrypted, set --passphrase or FCM_RECEIVER_PASSPHRASE".into())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Writes a notification as a line of JSON.
+fn write_line(mut out: impl Write, notification: &Notification) -> Result<(), Error> {
+    serde_json::to_writer(&mut out, &NotificationLine::new(notification))?;
+    writeln!(out)?;
+    Ok(out.flush()?)
+}
+
+/// Passes notifications to `handle` until interrupted.
+///
+/// The next notification is only read once the future returned by `handle` completes.
+async fn listen<F>(
+    mut client: Client,
+    mut handle: impl FnMut(Notification) -> F,
+) -> Result<(), Error>
+where
+    F: Future<Output = Result<(), Error>>,
+{
+    let mut notifications = pin!(client.notifications());
+    let receive = async {
+        while let Some(notification) = notifications.next().await {
+            match notification {
+                Ok(notification) => handle(notification).await?,
+                Err(error) => log::error!("{error}"),
+            }
+        }
+        Ok(())
+    };
+    tokio::select! {
+        result = receive => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
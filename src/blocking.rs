@@ -0,0 +1,116 @@
+//! Blocking API for synchronous code, running the async [`Client`](crate::Client) on an internal
+//! tokio runtime.
+//!
+//! The functions in this module panic when called from within an async runtime.
+use std::io;
+use std::ops::ControlFlow;
+
+use futures_util::StreamExt as _;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::mpsc;
+
+use crate::{ClientError, Credentials, Notification};
+
+/// Notifications buffered by [`Client::notifications()`] until the iterator takes them.
+const BUFFER: usize = 64;
+
+/// Blocking client for receiving FCM push notifications.
+///
+/// Configure the connection with the builder methods of the async client and convert it with
+/// [`From`].
+#[derive(Debug)]
+pub struct Client {
+    inner: crate::Client,
+}
+
+impl Client {
+    /// Constructs the client.
+    pub fn new(credentials: Credentials) -> Result<Self, ClientError> {
+        crate::Client::new(credentials).map(Self::from)
+    }
+
+    /// Registers the client with FCM and returns [`Credentials`] for the [`Client`].
+    pub fn register(sender_id: impl Into<String>) -> Result<Credentials, ClientError> {
+        runtime()?.block_on(crate::Client::register(sender_id))
+    }
+
+    /// Registers the client with FCM and returns [`Credentials`] for the [`Client`].
+    pub fn register_with(
+        sender_id: impl Into<String>,
+        server_key: impl AsRef<str>,
+    ) -> Result<Credentials, ClientError> {
+        runtime()?.block_on(crate::Client::register_with(sender_id, server_key))
+    }
+
+    /// Connects to FCM on a background thread and returns an iterator over the notifications.
+    ///
+    /// The connection keeps running while notifications are handled, buffering up to 64 received
+    /// meanwhile. Once the buffer is full the connection is not read and no heartbeats are sent
+    /// until the iterator takes a notification. If that takes longer than the heartbeat interval
+    /// the connection may be dropped, in which case the client reconnects and FCM redelivers
+    /// messages that were not acknowledged. Dropping the iterator closes the connection.
+    pub fn notifications(self) -> io::Result<Notifications> {
+        let runtime = runtime()?;
+        let (sender, receiver) = mpsc::channel(BUFFER);
+        let mut client = self.inner;
+        std::thread::Builder::new()
+            .name("fcm-receiver".into())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let mut notifications = std::pin::pin!(client.notifications());
+                    loop {
+                        tokio::select! {
+                            notification = notifications.next() => {
+                                let Some(notification) = notification else { break };
+                                if sender.send(notification).await.is_err() {
+                                    break;
+                                }
+                            }
+                            () = sender.closed() => break,
+                        }
+                    }
+                })
+            })?;
+
+        Ok(Notifications { receiver })
+    }
+
+    /// Passes notifications to `callback` until it returns [`ControlFlow::Break`] or the stream
+    /// of notifications ends.
+    pub fn for_each(
+        self,
+        mut callback: impl FnMut(Result<Notification, ClientError>) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        for notification in self.notifications()? {
+            if callback(notification).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<crate::Client> for Client {
+    fn from(inner: crate::Client) -> Self {
+        Self { inner }
+    }
+}
+
+/// Blocking iterator over the notifications of a [`Client`].
+#[derive(Debug)]
+pub struct Notifications {
+    receiver: mpsc::Receiver<Result<Notification, ClientError>>,
+}
+
+impl Iterator for Notifications {
+    type Item = Result<Notification, ClientError>;
+
+    /// Waits for the next notification.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.blocking_recv()
+    }
+}
+
+fn runtime() -> io::Result<Runtime> {
+    runtime::Builder::new_current_thread().enable_all().build()
+}
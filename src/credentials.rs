@@ -1,8 +1,17 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use ece::crypto::EcKeyComponents;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::fcm::FcmCredentials;
 use crate::gcm::GcmCredentials;
+use crate::{HeartbeatState, Secret};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -12,12 +21,179 @@ pub struct Credentials {
     pub fcm: FcmCredentials,
 }
 
+/// Version of the on-disk format written by [`Credentials::save()`].
+pub const FORMAT_VERSION: u32 = 1;
+
+/// On-disk envelope of [`Credentials`].
+///
+/// New optional fields may be added next to `credentials` without bumping [`FORMAT_VERSION`].
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Keys<T> {
-    pub private_key: T,
+struct Envelope<C> {
+    version: u32,
+    credentials: C,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    heartbeat_state: Option<HeartbeatState>,
+}
+
+/// Fields of any credentials file stored outside of the credentials, which may be encrypted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Unencrypted {
+    #[serde(default)]
+    heartbeat_state: Option<HeartbeatState>,
+}
+
+impl Credentials {
+    /// Loads credentials from a file written by [`Credentials::save()`].
+    ///
+    /// Files containing bare [`Credentials`] JSON, as written before the envelope was introduced,
+    /// are also accepted.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let contents = Zeroizing::new(fs::read(path)?);
+        let value: serde_json::Value = serde_json::from_slice(&contents)?;
+
+        if value.get("version").is_none() {
+            return Ok(serde_json::from_value(value)?);
+        }
+        if value.get("encrypted").is_some() {
+            return Err(StorageError::Encrypted);
+        }
+        let envelope: Envelope<Self> = serde_json::from_value(value)?;
+        if envelope.version > FORMAT_VERSION {
+            return Err(StorageError::UnsupportedVersion {
+                version: envelope.version,
+            });
+        }
+
+        Ok(envelope.credentials)
+    }
+
+    /// Saves the credentials to a file.
+    ///
+    /// The file is replaced atomically and, on unix, is only accessible by the owner.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), StorageError> {
+        let envelope = Envelope {
+            version: FORMAT_VERSION,
+            credentials: self,
+            heartbeat_state: stored_heartbeat_state(path.as_ref()),
+        };
+
+        let contents = Zeroizing::new(serde_json::to_vec_pretty(&envelope)?);
+        Ok(write_atomic(path.as_ref(), &contents)?)
+    }
+
+    /// Loads the heartbeat intervals stored in a credentials file, if any.
+    ///
+    /// The intervals are stored unencrypted, so this works for encrypted files without the
+    /// passphrase.
+    pub fn load_heartbeat_state(
+        path: impl AsRef<Path>,
+    ) -> Result<Option<HeartbeatState>, StorageError> {
+        let contents = Zeroizing::new(fs::read(path)?);
+        let unencrypted: Unencrypted = serde_json::from_slice(&contents)?;
+        Ok(unencrypted.heartbeat_state)
+    }
+
+    /// Stores heartbeat intervals learned by adaptive heartbeats in a credentials file, leaving
+    /// the credentials untouched.
+    ///
+    /// Saving the credentials again keeps the stored intervals.
+    pub fn save_heartbeat_state(
+        path: impl AsRef<Path>,
+        state: &HeartbeatState,
+    ) -> Result<(), StorageError> {
+        let path = path.as_ref();
+        let contents = Zeroizing::new(fs::read(path)?);
+        let mut value: serde_json::Value = serde_json::from_slice(&contents)?;
+        let Some(fields) = value.as_object_mut() else {
+            return Err(StorageError::Json(serde::de::Error::custom(
+                "credentials file is not a JSON object",
+            )));
+        };
+        fields.insert("heartbeatState".into(), serde_json::to_value(state)?);
+
+        let contents = Zeroizing::new(serde_json::to_vec_pretty(&value)?);
+        Ok(write_atomic(path, &contents)?)
+    }
+}
+
+/// Returns the heartbeat intervals stored in the file at `path`, ignoring any errors.
+pub(crate) fn stored_heartbeat_state(path: &Path) -> Option<HeartbeatState> {
+    Credentials::load_heartbeat_state(path).ok().flatten()
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let result = (|| {
+        let mut file = options.open(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+
+    // Persist the rename itself.
+    #[cfg(unix)]
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Returns a temporary path next to `path`, unique within the process so concurrent saves do not
+/// write to the same file.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}.{count}.tmp", std::process::id()));
+    path.with_file_name(file_name)
+}
+
+/// Errors returned when loading or saving [`Credentials`].
+#[derive(Debug, Error)]
+#[error("failed to access credentials file: {0}")]
+pub enum StorageError {
+    Io(#[from] io::Error),
+    Json(#[from] serde_json::Error),
+    Base64Decode(#[from] base64::DecodeError),
+    Crypto(#[from] openssl::error::ErrorStack),
+    #[error(
+        "unsupported credentials format version `{version}` (expected at most `{FORMAT_VERSION}`)"
+    )]
+    UnsupportedVersion {
+        version: u32,
+    },
+    #[error("unsupported credentials encryption")]
+    UnsupportedEncryption,
+    #[error("credentials are encrypted, use `Credentials::load_encrypted()`")]
+    Encrypted,
+    #[error("credentials are not encrypted, use `Credentials::load()`")]
+    NotEncrypted,
+    #[error("invalid passphrase or corrupted credentials")]
+    Decrypt,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Keys<T: Zeroize> {
+    pub private_key: Secret<T>,
     pub public_key: T,
-    pub auth_secret: T,
+    pub auth_secret: Secret<T>,
 }
 
 impl Keys<String> {
@@ -29,23 +205,135 @@ impl Keys<String> {
         let auth_secret = BASE64_URL_SAFE_NO_PAD.encode(auth_secret);
 
         Ok(Self {
-            private_key,
+            private_key: private_key.into(),
             public_key,
-            auth_secret,
+            auth_secret: auth_secret.into(),
+        })
+    }
+}
+
+/// Decoded [`Keys`] used to decrypt messages.
+pub(crate) struct DecryptionKeys {
+    pub(crate) auth_secret: Secret<Vec<u8>>,
+    private_key: Secret<Vec<u8>>,
+    public_key: Vec<u8>,
+}
+
+impl DecryptionKeys {
+    pub(crate) fn new(keys: &Keys<String>) -> Result<Self, base64::DecodeError> {
+        let keys = keys.base64_decode()?;
+
+        Ok(Self {
+            auth_secret: keys.auth_secret,
+            private_key: keys.private_key,
+            public_key: keys.public_key,
         })
     }
+
+    /// Returns the key pair in the form ece expects.
+    ///
+    /// [`EcKeyComponents`] cannot be zeroized, so it is only created for a single decryption
+    /// instead of being kept alongside the other keys.
+    pub(crate) fn ec_components(&self) -> EcKeyComponents {
+        EcKeyComponents::new(self.private_key.expose().clone(), self.public_key.clone())
+    }
 }
 
 impl<T> Keys<T>
 where
-    T: AsRef<[u8]>,
+    T: AsRef<[u8]> + Zeroize,
 {
     pub(crate) fn base64_decode(&self) -> Result<Keys<Vec<u8>>, base64::DecodeError> {
         Ok(Keys {
-            private_key: BASE64_URL_SAFE_NO_PAD.decode(&self.private_key)?,
+            private_key: BASE64_URL_SAFE_NO_PAD
+                .decode(self.private_key.expose())?
+                .into(),
             public_key: BASE64_URL_SAFE_NO_PAD.decode(&self.public_key)?,
-            auth_secret: BASE64_URL_SAFE_NO_PAD.decode(&self.auth_secret)?,
+            auth_secret: BASE64_URL_SAFE_NO_PAD
+                .decode(self.auth_secret.expose())?
+                .into(),
         })
     }
 }
 
+#[cfg(test)]
+impl Credentials {
+    /// Returns credentials with new keys and made up registration tokens.
+    pub(crate) fn for_test() -> Self {
+        Self {
+            keys: Keys::new().unwrap(),
+            gcm: GcmCredentials {
+                token: String::from("gcm-token").into(),
+                android_id: "1234".into(),
+                security_token: String::from("5678").into(),
+                app_id: "wp:receiver.push.com#test".into(),
+            },
+            fcm: FcmCredentials {
+                token: String::from("fcm-token").into(),
+                push_set: "push-set".into(),
+            },
+        }
+    }
+}
+
+/// Returns a path in the temporary directory unique to the test and process.
+#[cfg(test)]
+pub(crate) fn test_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("fcm-receiver-{}-{name}", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_paths_are_unique() {
+        let path = Path::new("dir/credentials.json");
+        let first = temp_path(path);
+        let second = temp_path(path);
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), path.parent());
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = test_path("round-trip.json");
+        let credentials = Credentials::for_test();
+        credentials.save(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let mut state = HeartbeatState::default();
+        state.intervals.insert("network".into(), 600_000);
+        Credentials::save_heartbeat_state(&path, &state).unwrap();
+        credentials.save(&path).unwrap();
+
+        let loaded = Credentials::load(&path).unwrap();
+        let heartbeat_state = Credentials::load_heartbeat_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.keys.public_key, credentials.keys.public_key);
+        assert_eq!(loaded.gcm.android_id, credentials.gcm.android_id);
+        assert_eq!(loaded.fcm.push_set, credentials.fcm.push_set);
+        assert_eq!(heartbeat_state.unwrap().intervals, state.intervals);
+    }
+
+    #[test]
+    fn loads_legacy_file() {
+        let path = test_path("legacy.json");
+        let credentials = Credentials::for_test();
+        fs::write(&path, serde_json::to_vec(&credentials).unwrap()).unwrap();
+
+        let loaded = Credentials::load(&path);
+        let heartbeat_state = Credentials::load_heartbeat_state(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap().keys.public_key, credentials.keys.public_key);
+        assert!(heartbeat_state.unwrap().is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::Instant;
+
+use crate::mcs::DataMessageStanza;
+
+/// Default number of persistent IDs remembered.
+pub(crate) const DEFAULT_CAPACITY: usize = 10_000;
+/// Default longest time a persistent ID is remembered, the maximum TTL of FCM messages.
+pub(crate) const DEFAULT_MAX_TTL: Duration = Duration::from_secs(28 * 24 * 60 * 60);
+/// Shortest time a persistent ID is remembered, covering redeliveries of messages whose TTL has
+/// passed by the time they arrive, e.g. because of clock skew.
+const MIN_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// Persistent IDs of delivered messages, remembered until the message could no longer be
+/// redelivered or the capacity is exceeded.
+#[derive(Debug)]
+pub(crate) struct DedupCache {
+    capacity: usize,
+    max_ttl: Duration,
+    expires_at: HashMap<String, Instant>,
+    /// Persistent IDs in insertion order, with the expiry they were inserted with.
+    order: VecDeque<(String, Instant)>,
+}
+
+impl DedupCache {
+    pub(crate) fn new(capacity: usize, max_ttl: Duration) -> Self {
+        Self {
+            capacity,
+            max_ttl,
+            expires_at: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn contains(&self, persistent_id: &str) -> bool {
+        self.expires_at
+            .get(persistent_id)
+            .is_some_and(|expires_at| Instant::now() < *expires_at)
+    }
+
+    /// Remembers the persistent ID of a message until its TTL has passed.
+    pub(crate) fn insert(&mut self, message: &DataMessageStanza) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let expires_at = now + self.ttl(message);
+        let persistent_id = message.persistent_id().to_string();
+        self.expires_at.insert(persistent_id.clone(), expires_at);
+        self.order.push_back((persistent_id, expires_at));
+        self.evict(now);
+    }
+
+    /// Forgets a persistent ID, so the message is delivered again if it is redelivered.
+    pub(crate) fn remove(&mut self, persistent_id: &str) {
+        self.expires_at.remove(persistent_id);
+    }
+
+    /// Returns how long the server may still redeliver a message, at least [`MIN_RETENTION`].
+    fn ttl(&self, message: &DataMessageStanza) -> Duration {
+        let ttl = match (message.ttl, message.sent) {
+            (Some(ttl), Some(sent)) if ttl > 0 => {
+                let expires_at = UNIX_EPOCH
+                    + Duration::from_millis(sent.max(0) as u64)
+                    + Duration::from_secs(ttl as u64);
+                expires_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+            }
+            (Some(ttl), None) if ttl > 0 => Duration::from_secs(ttl as u64),
+            _ => self.max_ttl,
+        };
+        ttl.max(MIN_RETENTION).min(self.max_ttl)
+    }
+
+    /// Drops expired entries from the front and the oldest entries beyond the capacity.
+    fn evict(&mut self, now: Instant) {
+        while let Some((persistent_id, expires_at)) = self.order.front() {
+            if self.order.len() <= self.capacity && now < *expires_at {
+                break;
+            }
+            // The entry is stale if the ID was inserted again later.
+            if self.expires_at.get(persistent_id) == Some(expires_at) {
+                self.expires_at.remove(persistent_id);
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(persistent_id: &str, ttl: Option<i32>, sent: Option<i64>) -> DataMessageStanza {
+        DataMessageStanza {
+            persistent_id: Some(persistent_id.into()),
+            ttl,
+            sent,
+            ..Default::default()
+        }
+    }
+
+    fn unix_millis(time: SystemTime) -> i64 {
+        time.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+    }
+
+    #[test]
+    fn evicts_oldest_beyond_capacity() {
+        let mut cache = DedupCache::new(2, DEFAULT_MAX_TTL);
+        for persistent_id in ["a", "b", "c"] {
+            cache.insert(&message(persistent_id, None, None));
+        }
+
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b"));
+        assert!(cache.contains("c"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_after_ttl() {
+        let mut cache = DedupCache::new(DEFAULT_CAPACITY, DEFAULT_MAX_TTL);
+        let ttl = 2 * MIN_RETENTION.as_secs() as i32;
+        cache.insert(&message("a", Some(ttl), None));
+
+        tokio::time::advance(MIN_RETENTION).await;
+        assert!(cache.contains("a"));
+        tokio::time::advance(MIN_RETENTION).await;
+        assert!(!cache.contains("a"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_expired_message_for_minimum_retention() {
+        let mut cache = DedupCache::new(DEFAULT_CAPACITY, DEFAULT_MAX_TTL);
+        let sent = unix_millis(SystemTime::now() - Duration::from_secs(120));
+        cache.insert(&message("a", Some(60), Some(sent)));
+
+        assert!(cache.contains("a"));
+        tokio::time::advance(MIN_RETENTION).await;
+        assert!(!cache.contains("a"));
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs;
+use std::path::Path;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use openssl::error::ErrorStack;
+use openssl::pkcs5::scrypt;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::credentials::{stored_heartbeat_state, write_atomic, StorageError, FORMAT_VERSION};
+use crate::{Credentials, HeartbeatState};
+
+const KDF: &str = "scrypt";
+const CIPHER: &str = "aes-256-gcm";
+/// Scrypt cost parameters used for new files.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+/// Upper bound for the memory scrypt may use when loading a file.
+const SCRYPT_MAX_MEM: u64 = 256 * 1024 * 1024;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// On-disk envelope of passphrase encrypted [`Credentials`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedEnvelope {
+    version: u32,
+    encrypted: EncryptedCredentials,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    heartbeat_state: Option<HeartbeatState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedCredentials {
+    kdf: KdfParams,
+    cipher: String,
+    /// Base64 encoded nonce.
+    nonce: String,
+    /// Base64 encoded ciphertext followed by the authentication tag.
+    ciphertext: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KdfParams {
+    algorithm: String,
+    /// Base64 encoded salt.
+    salt: String,
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl KdfParams {
+    fn generate() -> Result<Self, ErrorStack> {
+        let mut salt = [0u8; SALT_LEN];
+        rand_bytes(&mut salt)?;
+
+        Ok(Self {
+            algorithm: KDF.into(),
+            salt: BASE64_URL_SAFE_NO_PAD.encode(salt),
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        })
+    }
+
+    fn derive_key(&self, passphrase: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>, StorageError> {
+        if self.algorithm != KDF || self.log_n >= 64 {
+            return Err(StorageError::UnsupportedEncryption);
+        }
+        let salt = BASE64_URL_SAFE_NO_PAD.decode(&self.salt)?;
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        scrypt(
+            passphrase,
+            &salt,
+            1 << self.log_n,
+            self.r.into(),
+            self.p.into(),
+            SCRYPT_MAX_MEM,
+            key.as_mut_slice(),
+        )?;
+
+        Ok(key)
+    }
+
+    /// Additional authenticated data binding the ciphertext to the format `version` and kdf
+    /// parameters.
+    fn aad(&self, version: u32) -> Vec<u8> {
+        format!(
+            "fcm-receiver/{version}/{}/{}/{}/{}/{}",
+            self.algorithm, self.salt, self.log_n, self.r, self.p
+        )
+        .into_bytes()
+    }
+}
+
+impl Credentials {
+    /// Saves the credentials to a file, encrypted with a key derived from `passphrase`.
+    ///
+    /// The key is derived with scrypt and the credentials are encrypted with AES-256-GCM. The
+    /// file is replaced atomically and, on unix, is only accessible by the owner.
+    pub fn save_encrypted(
+        &self,
+        path: impl AsRef<Path>,
+        passphrase: impl AsRef<[u8]>,
+    ) -> Result<(), StorageError> {
+        let kdf = KdfParams::generate()?;
+        let key = kdf.derive_key(passphrase.as_ref())?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+
+        let plaintext = Zeroizing::new(serde_json::to_vec(self)?);
+        let mut tag = [0u8; TAG_LEN];
+        let mut ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            key.as_slice(),
+            Some(&nonce),
+            &kdf.aad(FORMAT_VERSION),
+            &plaintext,
+            &mut tag,
+        )?;
+        ciphertext.extend_from_slice(&tag);
+
+        let envelope = EncryptedEnvelope {
+            version: FORMAT_VERSION,
+            encrypted: EncryptedCredentials {
+                kdf,
+                cipher: CIPHER.into(),
+                nonce: BASE64_URL_SAFE_NO_PAD.encode(nonce),
+                ciphertext: BASE64_URL_SAFE_NO_PAD.encode(ciphertext),
+            },
+            heartbeat_state: stored_heartbeat_state(path.as_ref()),
+        };
+
+        Ok(write_atomic(
+            path.as_ref(),
+            &serde_json::to_vec_pretty(&envelope)?,
+        )?)
+    }
+
+    /// Loads credentials from a file written by [`Credentials::save_encrypted()`].
+    pub fn load_encrypted(
+        path: impl AsRef<Path>,
+        passphrase: impl AsRef<[u8]>,
+    ) -> Result<Self, StorageError> {
+        let value: serde_json::Value = serde_json::from_slice(&fs::read(path)?)?;
+        if value.get("encrypted").is_none() {
+            return Err(StorageError::NotEncrypted);
+        }
+        let envelope: EncryptedEnvelope = serde_json::from_value(value)?;
+        if envelope.version > FORMAT_VERSION {
+            return Err(StorageError::UnsupportedVersion {
+                version: envelope.version,
+            });
+        }
+        let encrypted = envelope.encrypted;
+        if encrypted.cipher != CIPHER {
+            return Err(StorageError::UnsupportedEncryption);
+        }
+
+        let key = encrypted.kdf.derive_key(passphrase.as_ref())?;
+        let nonce = BASE64_URL_SAFE_NO_PAD.decode(&encrypted.nonce)?;
+        let ciphertext = BASE64_URL_SAFE_NO_PAD.decode(&encrypted.ciphertext)?;
+        let Some(tag_start) = ciphertext.len().checked_sub(TAG_LEN) else {
+            return Err(StorageError::Decrypt);
+        };
+        let (ciphertext, tag) = ciphertext.split_at(tag_start);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            key.as_slice(),
+            Some(&nonce),
+            &encrypted.kdf.aad(envelope.version),
+            ciphertext,
+            tag,
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| StorageError::Decrypt)?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::test_path;
+
+    #[test]
+    fn round_trip() {
+        let path = test_path("encrypted-round-trip.json");
+        let credentials = Credentials::for_test();
+        credentials.save_encrypted(&path, "passphrase").unwrap();
+
+        let loaded = Credentials::load_encrypted(&path, "passphrase");
+        let wrong_passphrase = Credentials::load_encrypted(&path, "wrong");
+
+        // The format version is authenticated.
+        let mut value: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        value["version"] = 0.into();
+        fs::write(&path, value.to_string()).unwrap();
+        let downgraded = Credentials::load_encrypted(&path, "passphrase");
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(
+            loaded.keys.private_key.expose(),
+            credentials.keys.private_key.expose()
+        );
+        assert_eq!(loaded.gcm.security_token.expose(), "5678");
+        assert!(matches!(wrong_passphrase, Err(StorageError::Decrypt)));
+        assert!(matches!(downgraded, Err(StorageError::Decrypt)));
+    }
+}
@@ -0,0 +1,421 @@
+//! Republishing of notifications to local subscribers over a Unix domain socket.
+//!
+//! Frames in both directions are JSON objects prefixed with their length as a big-endian
+//! `u32`. A subscriber first sends a [`Subscribe`] frame, then receives the last replayed
+//! notifications matching its filters followed by new ones, each as a [`Published`] frame.
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use futures_util::StreamExt as _;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio::time;
+
+use crate::credentials::temp_path;
+use crate::log;
+use crate::{Client, Notification};
+
+/// Largest [`Subscribe`] frame accepted.
+const MAX_SUBSCRIBE_SIZE: u32 = 64 * 1024;
+/// Time a subscriber has to send its [`Subscribe`] frame.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Notifications buffered per subscriber before it is disconnected as too slow.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Filters sent by a subscriber, empty lists match every notification.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Subscribe {
+    /// Senders (`from`) to receive notifications from.
+    pub senders: Vec<String>,
+    /// Categories to receive notifications for.
+    pub categories: Vec<String>,
+}
+
+impl Subscribe {
+    fn matches(&self, published: &Published) -> bool {
+        (self.senders.is_empty() || self.senders.contains(&published.from))
+            && (self.categories.is_empty() || self.categories.contains(&published.category))
+    }
+}
+
+/// Notification as sent to subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Published {
+    pub persistent_id: String,
+    pub from: String,
+    pub category: String,
+    pub app_id: String,
+    pub sent: Option<i64>,
+    pub ttl: Option<i32>,
+    pub encrypted: bool,
+    pub trusted: Option<bool>,
+    pub data: HashMap<String, String>,
+    /// Unpadded url-safe base64 encoded payload.
+    pub payload: String,
+}
+
+impl From<&Notification> for Published {
+    fn from(notification: &Notification) -> Self {
+        Self {
+            persistent_id: notification.persistent_id.clone(),
+            from: notification.from.clone(),
+            category: notification.category.clone(),
+            app_id: notification.app_id.clone(),
+            sent: notification.sent,
+            ttl: notification.ttl,
+            encrypted: notification.encrypted,
+            trusted: notification.trusted,
+            data: notification.data.clone(),
+            payload: BASE64_URL_SAFE_NO_PAD.encode(&notification.payload),
+        }
+    }
+}
+
+/// Published notification with its encoded frame.
+#[derive(Debug)]
+struct Frame {
+    published: Published,
+    bytes: Vec<u8>,
+}
+
+/// Fan-out server owning a [`Client`].
+#[derive(Debug, Clone)]
+pub struct FanOut {
+    socket: PathBuf,
+    replay: usize,
+    mode: u32,
+}
+
+impl FanOut {
+    /// Creates a server listening on the socket at `path`, only accessible by the owner.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: path.into(),
+            replay: 0,
+            mode: 0o600,
+        }
+    }
+
+    /// Sends the last `count` notifications to new subscribers.
+    pub fn with_replay(mut self, count: usize) -> Self {
+        self.replay = count;
+        self
+    }
+
+    /// Sets the permissions of the socket file.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Binds the socket with its final permissions before it becomes reachable.
+    ///
+    /// The socket is created in a new directory only accessible by the owner, has its mode set
+    /// and is then linked to its path, which fails if the path exists.
+    fn bind(&self) -> io::Result<UnixListener> {
+        let dir = temp_path(&self.socket);
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let result = (|| {
+            let path = dir.join("socket");
+            let listener = UnixListener::bind(&path)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(self.mode))?;
+            std::fs::hard_link(&path, &self.socket)?;
+            Ok(listener)
+        })();
+        let _ = std::fs::remove_dir_all(&dir);
+        result
+    }
+
+    /// Removes the socket file left by a server that is no longer running.
+    async fn remove_stale_socket(&self) -> io::Result<()> {
+        let metadata = match std::fs::symlink_metadata(&self.socket) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", self.socket.display()),
+            ));
+        }
+        if UnixStream::connect(&self.socket).await.is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another server is listening on {}", self.socket.display()),
+            ));
+        }
+        std::fs::remove_file(&self.socket)
+    }
+
+    /// Receives notifications with `client` and publishes them until the stream ends.
+    ///
+    /// A stale socket file left by a previous server is replaced. Fails if the path is not a
+    /// socket or another server is listening on it.
+    pub async fn run(self, mut client: Client) -> io::Result<()> {
+        self.remove_stale_socket().await?;
+        let listener = self.bind()?;
+        log::info!("publishing notifications on {}", self.socket.display());
+
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        let mut replay = Replay::new(self.replay);
+        let mut notifications = std::pin::pin!(client.notifications());
+        loop {
+            tokio::select! {
+                result = listener.accept() => match result {
+                    Ok((stream, _)) => {
+                        let replay = replay.frames.iter().cloned().collect();
+                        tokio::spawn(subscriber(stream, replay, sender.subscribe()));
+                    }
+                    Err(error) => log::error!("failed to accept subscriber: {error}"),
+                },
+                notification = notifications.next() => match notification {
+                    Some(Ok(notification)) => {
+                        let frame = match encode(&notification) {
+                            Ok(frame) => Arc::new(frame),
+                            Err(error) => {
+                                log::error!("failed to encode notification: {error}");
+                                continue;
+                            }
+                        };
+                        replay.push(frame.clone());
+                        // Fails only without subscribers.
+                        let _ = sender.send(frame);
+                    }
+                    Some(Err(error)) => log::error!("{error}"),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Last notifications sent to new subscribers.
+#[derive(Debug)]
+struct Replay {
+    frames: VecDeque<Arc<Frame>>,
+    capacity: usize,
+}
+
+impl Replay {
+    fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, frame: Arc<Frame>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+}
+
+fn encode(notification: &Notification) -> serde_json::Result<Frame> {
+    let published = Published::from(notification);
+    let json = serde_json::to_vec(&published)?;
+    let mut bytes = Vec::with_capacity(json.len() + 4);
+    bytes.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&json);
+    Ok(Frame { published, bytes })
+}
+
+async fn subscriber(
+    mut stream: UnixStream,
+    replay: Vec<Arc<Frame>>,
+    mut receiver: broadcast::Receiver<Arc<Frame>>,
+) {
+    let filter = match time::timeout(SUBSCRIBE_TIMEOUT, read_subscribe(&mut stream)).await {
+        Ok(Ok(filter)) => filter,
+        Ok(Err(error)) => {
+            log::warn!("invalid subscribe frame: {error}");
+            return;
+        }
+        Err(_) => {
+            log::warn!("subscriber did not subscribe in time");
+            return;
+        }
+    };
+    log::debug!("subscriber connected: {filter:?}");
+
+    for frame in replay {
+        if filter.matches(&frame.published) && stream.write_all(&frame.bytes).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        match receiver.recv().await {
+            Ok(frame) => {
+                if filter.matches(&frame.published) && stream.write_all(&frame.bytes).await.is_err()
+                {
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                log::warn!("disconnecting subscriber lagging by {count} notifications");
+                return;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn read_subscribe(stream: &mut UnixStream) -> io::Result<Subscribe> {
+    let len = stream.read_u32().await?;
+    if len > MAX_SUBSCRIBE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds {MAX_SUBSCRIBE_SIZE}"),
+        ));
+    }
+    let mut json = vec![0; len as usize];
+    stream.read_exact(&mut json).await?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::test_path;
+    use crate::DataMessageStanza;
+
+    #[tokio::test]
+    async fn binds_socket_with_mode() {
+        let path = test_path("fanout.sock");
+        let fan_out = FanOut::new(&path).with_mode(0o660);
+
+        let listener = fan_out.bind().unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        let connected = UnixStream::connect(&path).await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        assert!(connected.is_ok());
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_replace_other_files() {
+        let path = test_path("fanout-regular");
+        std::fs::write(&path, b"{}").unwrap();
+
+        let result = FanOut::new(&path).remove_stale_socket().await;
+        let bound = FanOut::new(&path).bind();
+        let contents = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(bound.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(contents.unwrap(), b"{}");
+    }
+
+    #[tokio::test]
+    async fn replaces_only_stale_sockets() {
+        let path = test_path("fanout-stale.sock");
+        let fan_out = FanOut::new(&path);
+        let listener = fan_out.bind().unwrap();
+
+        let live = fan_out.remove_stale_socket().await;
+        drop(listener);
+        let stale = fan_out.remove_stale_socket().await;
+        let removed = !path.exists();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(live.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        assert!(stale.is_ok());
+        assert!(removed);
+    }
+
+    fn frame(from: &str, category: &str) -> Arc<Frame> {
+        let message = DataMessageStanza {
+            persistent_id: Some(format!("0:{from}-{category}")),
+            from: from.into(),
+            category: category.into(),
+            ..Default::default()
+        };
+        Arc::new(encode(&Notification::new(message, None)).unwrap())
+    }
+
+    #[test]
+    fn filters_by_sender_and_category() {
+        let published = &frame("1234", "org.example.app").published;
+        let filter = |senders: &[&str], categories: &[&str]| Subscribe {
+            senders: senders.iter().map(|&sender| sender.into()).collect(),
+            categories: categories.iter().map(|&category| category.into()).collect(),
+        };
+
+        assert!(filter(&[], &[]).matches(published));
+        assert!(filter(&["1234", "5678"], &[]).matches(published));
+        assert!(filter(&["1234"], &["org.example.app"]).matches(published));
+        assert!(!filter(&["5678"], &[]).matches(published));
+        assert!(!filter(&["1234"], &["org.example.other"]).matches(published));
+    }
+
+    #[test]
+    fn replays_last_notifications() {
+        let mut replay = Replay::new(2);
+        for from in ["1", "2", "3"] {
+            replay.push(frame(from, "app"));
+        }
+        let mut disabled = Replay::new(0);
+        disabled.push(frame("1", "app"));
+
+        let replayed: Vec<_> = replay
+            .frames
+            .iter()
+            .map(|frame| frame.published.from.as_str())
+            .collect();
+        assert_eq!(replayed, ["2", "3"]);
+        assert!(disabled.frames.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_matching_replayed_and_new_notifications() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (sender, receiver) = broadcast::channel(SUBSCRIBER_BUFFER);
+        let replay = vec![frame("1234", "replayed"), frame("5678", "replayed")];
+        tokio::spawn(subscriber(server, replay, receiver));
+
+        let subscribe = serde_json::to_vec(&Subscribe {
+            senders: vec!["1234".into()],
+            categories: Vec::new(),
+        })
+        .unwrap();
+        client.write_u32(subscribe.len() as u32).await.unwrap();
+        client.write_all(&subscribe).await.unwrap();
+        for frame in [frame("5678", "new"), frame("1234", "new")] {
+            sender.send(frame).unwrap();
+        }
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Ok(len) = client.read_u32().await {
+            let mut json = vec![0; len as usize];
+            client.read_exact(&mut json).await.unwrap();
+            let published: Published = serde_json::from_slice(&json).unwrap();
+            received.push((published.from, published.category));
+        }
+        assert_eq!(
+            received,
+            [
+                ("1234".to_string(), "replayed".to_string()),
+                ("1234".to_string(), "new".to_string())
+            ]
+        );
+    }
+}
@@ -1,4 +1,6 @@
 use crate::credentials::Keys;
+use crate::log;
+use crate::Secret;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -10,9 +12,9 @@ pub(crate) mod endpoint {
 #[derive(Debug, Serialize)]
 struct RegisterForm {
     authorized_entity: String,
-    endpoint: String,
+    endpoint: Secret<String>,
     encryption_key: String,
-    encryption_auth: String,
+    encryption_auth: Secret<String>,
 }
 
 impl RegisterForm {
@@ -29,9 +31,9 @@ impl RegisterForm {
 
         Self {
             authorized_entity: sender_id.into(),
-            endpoint: format!("{endpoint}/{token}"),
+            endpoint: format!("{endpoint}/{token}").into(),
             encryption_key: public_key,
-            encryption_auth: auth_secret,
+            encryption_auth: auth_secret.into(),
         }
     }
 }
@@ -39,7 +41,7 @@ impl RegisterForm {
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FcmCredentials {
-    pub token: String,
+    pub token: Secret<String>,
     pub push_set: String,
 }
 
@@ -49,6 +51,10 @@ pub struct Registration {
     pub fcm: FcmCredentials,
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "fcm_register", skip_all, err)
+)]
 pub async fn register(
     sender_id: impl Into<String>,
     token: impl AsRef<str>,
@@ -61,7 +67,7 @@ pub async fn register(
         sender_id,
         token.as_ref(),
         &keys.public_key,
-        &keys.auth_secret,
+        keys.auth_secret.expose(),
     );
 
     log::debug!("{form:#?}");
@@ -2,12 +2,15 @@ pub mod proto {
     #![allow(clippy::enum_variant_names)]
     include!(concat!(env!("OUT_DIR"), "/checkin_proto.rs"));
 }
+use prost::Message;
 use proto::*;
 use reqwest::Client as Http;
-use prost::Message;
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::log;
+use crate::Secret;
+
 pub const CHECKIN_URL: &str = "https://android.clients.google.com/checkin";
 pub const REGISTER_URL: &str = "https://android.clients.google.com/c2dm/register3";
 
@@ -33,6 +36,10 @@ impl AndroidCheckinRequest {
     }
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip(http, security_token), err)
+)]
 pub async fn check_in(
     http: &Http,
     android_id: Option<u64>,
@@ -74,11 +81,31 @@ pub async fn register(
     app_id: impl AsRef<str>,
     server_key: impl AsRef<str>,
 ) -> Result<GcmCredentials, RegisterError> {
-    let server_key = server_key.as_ref();
     let response = check_in(http, None, None).await?;
     let android_id = response.android_id().to_string();
     let security_token = response.security_token().to_string();
 
+    register_app(http, android_id, security_token, app_id, server_key).await
+}
+
+/// Registers an app with an already checked-in device.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "gcm_register",
+        skip_all,
+        fields(android_id = %android_id, app_id = app_id.as_ref()),
+        err,
+    )
+)]
+pub async fn register_app(
+    http: &Http,
+    android_id: String,
+    security_token: String,
+    app_id: impl AsRef<str>,
+    server_key: impl AsRef<str>,
+) -> Result<GcmCredentials, RegisterError> {
+    let server_key = server_key.as_ref();
     let form = RegisterForm {
         app: "org.chromium.linux".into(),
         x_subtype: app_id.as_ref().into(),
@@ -97,8 +124,6 @@ pub async fn register(
         .form(&form)
         .build()?;
 
-    log::debug!("{request:#?}");
-
     let response = http.execute(request).await?.error_for_status()?;
 
     log::debug!("{response:#?}");
@@ -106,9 +131,9 @@ pub async fn register(
     let body = response.text().await?;
 
     Ok(GcmCredentials {
-        token: body.split('=').nth(1).unwrap().into(),
+        token: body.split('=').nth(1).unwrap().to_string().into(),
         app_id: app_id.as_ref().into(),
-        security_token,
+        security_token: security_token.into(),
         android_id,
     })
 }
@@ -120,11 +145,11 @@ pub enum RegisterError {
     Http(#[from] reqwest::Error),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GcmCredentials {
-    pub token: String,
+    pub token: Secret<String>,
     pub android_id: String,
-    pub security_token: String,
+    pub security_token: Secret<String>,
     pub app_id: String,
 }
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+
+use crate::mcs::{HeartbeatConfig, HeartbeatStat};
+
+/// Interval used when heartbeats are not adaptive and the server does not provide one.
+pub(crate) const DEFAULT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Interval adaptive heartbeats start probing from on a network without a learned interval.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_secs(2 * 60);
+/// Longest interval adaptive heartbeats probe for.
+pub(crate) const MAX_INTERVAL: Duration = Duration::from_secs(28 * 60);
+/// Amount an adaptive interval grows by after each acknowledged heartbeat.
+pub(crate) const INTERVAL_STEP: Duration = Duration::from_secs(2 * 60);
+/// Time to wait for a heartbeat ack before the connection is considered dead.
+pub(crate) const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Heartbeat intervals learned by adaptive heartbeats, persisted across connections.
+///
+/// Intervals are stored per network, identified by the local ip address of the connection.
+/// Behind NAT this key is ambiguous: different networks handing out the same private address,
+/// e.g. two home routers using `192.168.1.0/24`, share an interval. An interval that is too long
+/// for the current network costs one ack timeout before adaptive heartbeats fall back to a
+/// shorter one.
+///
+/// [`Credentials::save_heartbeat_state()`](crate::Credentials::save_heartbeat_state) stores the
+/// intervals in the credentials file so they survive restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartbeatState {
+    /// Longest acknowledged interval in milliseconds, by network.
+    pub intervals: HashMap<String, u64>,
+    /// Shortest interval in milliseconds that timed out, by network. Adaptive heartbeats do not
+    /// probe for intervals at or above it.
+    #[serde(default)]
+    pub ceilings: HashMap<String, u64>,
+}
+
+impl HeartbeatState {
+    /// Returns the learned interval for `network`, if any.
+    pub fn interval(&self, network: &str) -> Option<Duration> {
+        self.intervals
+            .get(network)
+            .map(|&interval_ms| Duration::from_millis(interval_ms))
+    }
+
+    /// Returns the shortest interval that timed out on `network`, if any.
+    pub fn ceiling(&self, network: &str) -> Option<Duration> {
+        self.ceilings
+            .get(network)
+            .map(|&ceiling_ms| Duration::from_millis(ceiling_ms))
+    }
+
+    fn set_interval(&mut self, network: impl Into<String>, interval: Duration) {
+        self.intervals
+            .insert(network.into(), interval.as_millis() as u64);
+    }
+
+    fn set_ceiling(&mut self, network: &str, interval: Duration) {
+        let ceiling = self
+            .ceiling(network)
+            .map_or(interval, |ceiling| ceiling.min(interval));
+        self.ceilings
+            .insert(network.into(), ceiling.as_millis() as u64);
+    }
+}
+
+/// Heartbeat scheduling for a single connection.
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    network: String,
+    adaptive: bool,
+    interval: Duration,
+    next_ping: Instant,
+    ack_deadline: Option<Instant>,
+}
+
+impl Heartbeat {
+    /// Constructs the type for a new connection on `network`.
+    pub(crate) fn new(network: impl Into<String>, adaptive: bool, state: &HeartbeatState) -> Self {
+        let network = network.into();
+        let interval = match adaptive {
+            true => state.interval(&network).unwrap_or(MIN_INTERVAL),
+            false => DEFAULT_INTERVAL,
+        };
+
+        Self {
+            network,
+            adaptive,
+            interval,
+            next_ping: Instant::now() + interval,
+            ack_deadline: None,
+        }
+    }
+
+    /// Returns the instant at which the next ping should be sent or, if a ping has been sent,
+    /// at which its ack times out.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.ack_deadline.unwrap_or(self.next_ping)
+    }
+
+    /// Returns true if a ping has been sent and not yet acknowledged.
+    pub(crate) fn awaiting_ack(&self) -> bool {
+        self.ack_deadline.is_some()
+    }
+
+    /// Applies the heartbeat configuration sent by the server, unless heartbeats are adaptive.
+    pub(crate) fn configure(&mut self, config: &HeartbeatConfig) {
+        if self.adaptive || config.interval_ms() <= 0 {
+            return;
+        }
+        self.interval = Duration::from_millis(config.interval_ms() as u64);
+        self.on_activity();
+    }
+
+    /// Postpones the next ping after any traffic from the server.
+    pub(crate) fn on_activity(&mut self) {
+        if !self.awaiting_ack() {
+            self.next_ping = Instant::now() + self.interval;
+        }
+    }
+
+    /// Records a sent ping.
+    pub(crate) fn on_ping_sent(&mut self) {
+        self.ack_deadline = Some(Instant::now() + ACK_TIMEOUT);
+    }
+
+    /// Returns the time since the ping awaiting an ack was sent.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn round_trip(&self) -> Option<Duration> {
+        let sent_at = self.ack_deadline? - ACK_TIMEOUT;
+        Some(Instant::now().saturating_duration_since(sent_at))
+    }
+
+    /// Records a received ack, returning a stat to report if heartbeats are adaptive.
+    ///
+    /// Adaptive heartbeats remember the acknowledged interval and probe for a longer one, below
+    /// the shortest interval that timed out on the network.
+    pub(crate) fn on_ack(&mut self, state: &mut HeartbeatState) -> Option<HeartbeatStat> {
+        self.ack_deadline.take()?;
+        let stat = self.stat(false);
+        if self.adaptive {
+            state.set_interval(&self.network, self.interval);
+            let probe = MAX_INTERVAL.min(self.interval + INTERVAL_STEP);
+            if state
+                .ceiling(&self.network)
+                .is_none_or(|ceiling| probe < ceiling)
+            {
+                self.interval = probe;
+            }
+        }
+        self.on_activity();
+        self.adaptive.then_some(stat)
+    }
+
+    /// Records an ack timeout, returning a stat to report if heartbeats are adaptive.
+    ///
+    /// Adaptive heartbeats fall back to a shorter interval on the network and stop probing for
+    /// the interval that timed out.
+    pub(crate) fn on_timeout(&mut self, state: &mut HeartbeatState) -> Option<HeartbeatStat> {
+        self.ack_deadline = None;
+        if !self.adaptive {
+            return None;
+        }
+        state.set_ceiling(&self.network, self.interval);
+        let learned = state.interval(&self.network).unwrap_or(self.interval);
+        let fallback = learned
+            .min(self.interval.saturating_sub(INTERVAL_STEP))
+            .max(MIN_INTERVAL);
+        state.set_interval(&self.network, fallback);
+        Some(self.stat(true))
+    }
+
+    fn stat(&self, timeout: bool) -> HeartbeatStat {
+        HeartbeatStat {
+            ip: self.network.clone(),
+            timeout,
+            interval_ms: self.interval.as_millis() as i32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORK: &str = "192.168.1.2";
+
+    fn minutes(minutes: u64) -> Duration {
+        Duration::from_secs(minutes * 60)
+    }
+
+    /// Sends a ping and receives its ack, returning the next interval.
+    fn ack(heartbeat: &mut Heartbeat, state: &mut HeartbeatState) -> Duration {
+        heartbeat.on_ping_sent();
+        assert!(heartbeat.on_ack(state).is_some());
+        heartbeat.interval
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn grows_interval_up_to_max() {
+        let mut state = HeartbeatState::default();
+        let mut heartbeat = Heartbeat::new(NETWORK, true, &state);
+        assert_eq!(heartbeat.deadline(), Instant::now() + MIN_INTERVAL);
+
+        assert_eq!(
+            ack(&mut heartbeat, &mut state),
+            MIN_INTERVAL + INTERVAL_STEP
+        );
+        assert_eq!(state.interval(NETWORK), Some(MIN_INTERVAL));
+        assert_eq!(
+            heartbeat.deadline(),
+            Instant::now() + MIN_INTERVAL + INTERVAL_STEP
+        );
+
+        for _ in 0..20 {
+            ack(&mut heartbeat, &mut state);
+        }
+        assert_eq!(heartbeat.interval, MAX_INTERVAL);
+        assert_eq!(state.interval(NETWORK), Some(MAX_INTERVAL));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn falls_back_and_stops_probing_after_timeout() {
+        let mut state = HeartbeatState::default();
+        state.set_interval(NETWORK, minutes(10));
+        let mut heartbeat = Heartbeat::new(NETWORK, true, &state);
+        assert_eq!(ack(&mut heartbeat, &mut state), minutes(12));
+
+        heartbeat.on_ping_sent();
+        let stat = heartbeat.on_timeout(&mut state).unwrap();
+        assert!(stat.timeout);
+        assert_eq!(state.interval(NETWORK), Some(minutes(10)));
+        assert_eq!(state.ceiling(NETWORK), Some(minutes(12)));
+
+        // The next connection stays at the learned interval.
+        let mut heartbeat = Heartbeat::new(NETWORK, true, &state);
+        assert_eq!(heartbeat.interval, minutes(10));
+        assert_eq!(ack(&mut heartbeat, &mut state), minutes(10));
+        assert_eq!(ack(&mut heartbeat, &mut state), minutes(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn falls_back_below_timed_out_learned_interval() {
+        let mut state = HeartbeatState::default();
+        state.set_interval(NETWORK, minutes(10));
+        let mut heartbeat = Heartbeat::new(NETWORK, true, &state);
+
+        heartbeat.on_ping_sent();
+        heartbeat.on_timeout(&mut state);
+
+        assert_eq!(state.interval(NETWORK), Some(minutes(8)));
+        assert_eq!(state.ceiling(NETWORK), Some(minutes(10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_server_config_when_adaptive() {
+        let config = HeartbeatConfig {
+            interval_ms: Some(minutes(5).as_millis() as i32),
+            ..Default::default()
+        };
+        let mut state = HeartbeatState::default();
+
+        let mut adaptive = Heartbeat::new(NETWORK, true, &state);
+        adaptive.configure(&config);
+        assert_eq!(adaptive.interval, MIN_INTERVAL);
+
+        let mut fixed = Heartbeat::new(NETWORK, false, &state);
+        assert_eq!(fixed.interval, DEFAULT_INTERVAL);
+        fixed.configure(&config);
+        assert_eq!(fixed.deadline(), Instant::now() + minutes(5));
+        fixed.on_ping_sent();
+        assert!(fixed.on_ack(&mut state).is_none());
+        assert!(state.intervals.is_empty());
+    }
+}
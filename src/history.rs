@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mcs::{client_event, ClientEvent};
+use crate::ClientError;
+
+/// Maximum number of events kept in the local connection history.
+pub(crate) const MAX_HISTORY: usize = 100;
+/// Maximum number of events reported on login, further events are counted as discarded.
+pub(crate) const MAX_REPORTED_EVENTS: usize = 30;
+/// Network type reported to the server, matching the login request.
+const NETWORK_TYPE: i32 = 1;
+
+/// A connection attempt to FCM.
+#[derive(Debug, Clone)]
+pub struct ConnectionEvent {
+    /// Time the connection attempt was started.
+    pub started_at: SystemTime,
+    /// Time the connection was established, if the attempt succeeded.
+    pub established_at: Option<SystemTime>,
+    /// Time the connection attempt failed or the established connection was lost.
+    pub ended_at: Option<SystemTime>,
+    /// OS error code of a failed attempt, if any.
+    pub error_code: Option<i32>,
+    /// Description of the error of a failed attempt.
+    pub error: Option<String>,
+}
+
+impl ConnectionEvent {
+    /// Returns true if the connection was established.
+    pub fn is_successful(&self) -> bool {
+        self.established_at.is_some()
+    }
+
+    fn to_client_event(&self) -> ClientEvent {
+        let r#type = match self.is_successful() {
+            true => client_event::Type::SuccessfulConnection,
+            false => client_event::Type::FailedConnection,
+        };
+
+        ClientEvent {
+            r#type: Some(r#type.into()),
+            network_type: NETWORK_TYPE.into(),
+            time_connection_started_ms: Some(unix_millis(self.started_at)),
+            time_connection_established_ms: self.established_at.map(unix_millis),
+            time_connection_ended_ms: self.ended_at.map(unix_millis),
+            error_code: self.error_code,
+            ..Default::default()
+        }
+    }
+}
+
+/// Records connection attempts for the local history and for reporting on login.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionHistory {
+    events: VecDeque<ConnectionEvent>,
+    /// Events not yet reported to the server.
+    unreported: Vec<ConnectionEvent>,
+    /// Number of events dropped from `unreported` since the last report.
+    discarded: u32,
+    /// Number of unreported and discarded events included in the latest login request.
+    sent: (usize, u32),
+}
+
+impl ConnectionHistory {
+    /// Returns the recorded events, oldest first.
+    pub(crate) fn events(&self) -> impl ExactSizeIterator<Item = &ConnectionEvent> {
+        self.events.iter()
+    }
+
+    /// Records a failed connection attempt.
+    pub(crate) fn on_failed(&mut self, started_at: SystemTime, error: &ClientError) {
+        let error_code = match error {
+            ClientError::Network(error) => error.raw_os_error(),
+            _ => None,
+        };
+        let event = ConnectionEvent {
+            started_at,
+            established_at: None,
+            ended_at: Some(SystemTime::now()),
+            error_code,
+            error: Some(error.to_string()),
+        };
+
+        self.report(&event);
+        self.push(event);
+    }
+
+    /// Records an established connection.
+    pub(crate) fn on_connected(&mut self, started_at: SystemTime) {
+        let event = ConnectionEvent {
+            started_at,
+            established_at: Some(SystemTime::now()),
+            ended_at: None,
+            error_code: None,
+            error: None,
+        };
+
+        self.report(&event);
+        self.push(event);
+    }
+
+    /// Records the loss of the latest established connection.
+    pub(crate) fn on_disconnected(&mut self) {
+        let ended_at = SystemTime::now();
+        let latest = [self.events.back_mut(), self.unreported.last_mut()];
+        for event in latest.into_iter().flatten() {
+            if event.is_successful() && event.ended_at.is_none() {
+                event.ended_at = Some(ended_at);
+            }
+        }
+    }
+
+    /// Returns the events to report in the next login request.
+    pub(crate) fn client_events(&self) -> Vec<ClientEvent> {
+        let mut events: Vec<_> = self
+            .unreported
+            .iter()
+            .map(ConnectionEvent::to_client_event)
+            .collect();
+        if self.discarded > 0 {
+            events.push(ClientEvent {
+                r#type: Some(client_event::Type::DiscardedEvents.into()),
+                number_discarded_events: Some(self.discarded),
+                ..Default::default()
+            });
+        }
+        events
+    }
+
+    /// Remembers the events included in a login request sent to the server.
+    pub(crate) fn on_login_request(&mut self) {
+        self.sent = (self.unreported.len(), self.discarded);
+    }
+
+    /// Clears the events reported in the login request of a successful login.
+    ///
+    /// Events recorded after the request was built, such as the connection the login happened
+    /// on, are kept for the next login.
+    pub(crate) fn on_login(&mut self) {
+        let (events, discarded) = std::mem::take(&mut self.sent);
+        self.unreported.drain(..events.min(self.unreported.len()));
+        self.discarded = self.discarded.saturating_sub(discarded);
+    }
+
+    fn report(&mut self, event: &ConnectionEvent) {
+        match self.unreported.len() < MAX_REPORTED_EVENTS {
+            true => self.unreported.push(event.clone()),
+            false => self.discarded += 1,
+        }
+    }
+
+    fn push(&mut self, event: ConnectionEvent) {
+        if self.events.len() == MAX_HISTORY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_connection_after_it_ended() {
+        let mut history = ConnectionHistory::default();
+        let error = ClientError::Network(std::io::ErrorKind::ConnectionRefused.into());
+        history.on_failed(SystemTime::now(), &error);
+
+        // The login request of the next attempt is built before the connection is recorded.
+        let events = history.client_events();
+        history.on_login_request();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type(), client_event::Type::FailedConnection);
+        history.on_connected(SystemTime::now());
+        history.on_login();
+        history.on_disconnected();
+
+        let events = history.client_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type(), client_event::Type::SuccessfulConnection);
+        assert!(events[0].time_connection_established_ms.is_some());
+        assert!(events[0].time_connection_ended_ms.is_some());
+        history.on_login_request();
+        history.on_connected(SystemTime::now());
+        history.on_login();
+
+        let events = history.client_events();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].time_connection_ended_ms.is_none());
+    }
+
+    #[test]
+    fn keeps_discarded_count_until_reported() {
+        let mut history = ConnectionHistory::default();
+        for _ in 0..MAX_REPORTED_EVENTS + 2 {
+            history.on_connected(SystemTime::now());
+        }
+
+        let events = history.client_events();
+        assert_eq!(events.len(), MAX_REPORTED_EVENTS + 1);
+        assert_eq!(events[MAX_REPORTED_EVENTS].number_discarded_events, Some(2));
+        history.on_login_request();
+        history.on_login();
+        assert!(history.client_events().is_empty());
+    }
+}
@@ -0,0 +1,362 @@
+use std::fs;
+use std::path::Path;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use openssl::ec::EcKey;
+use openssl::pkey::PKey;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::fcm::FcmCredentials;
+use crate::gcm::GcmCredentials;
+use crate::{Credentials, Keys, Secret};
+
+/// Length of a raw P-256 private key.
+const PRIVATE_KEY_LEN: usize = 32;
+
+/// Credentials file formats of other FCM clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// [MatthieuLemoine/push-receiver](https://github.com/MatthieuLemoine/push-receiver) and its
+    /// forks, with camelCase keys and base64 encoded raw key material.
+    PushReceiver,
+    /// Python [push_receiver](https://github.com/Francesco149/push_receiver), with `public`,
+    /// `private` and `secret` keys and a DER encoded private key.
+    PythonPushReceiver,
+}
+
+/// [`Credentials`] imported from another FCM client.
+#[derive(Debug)]
+pub struct ImportedCredentials {
+    pub credentials: Credentials,
+    /// Persistent IDs of messages already received by the other client.
+    pub persistent_ids: Vec<String>,
+}
+
+impl ImportedCredentials {
+    /// Imports credentials from a file, detecting the format.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ImportError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Imports credentials from JSON, detecting the format.
+    pub fn parse(json: &str) -> Result<Self, ImportError> {
+        let value: Value = serde_json::from_str(json)?;
+        let format = detect(&value).ok_or(ImportError::UnknownFormat)?;
+        Self::from_value(format, &value)
+    }
+
+    /// Imports credentials from JSON in the given `format`.
+    pub fn parse_as(format: ImportFormat, json: &str) -> Result<Self, ImportError> {
+        Self::from_value(format, &serde_json::from_str(json)?)
+    }
+
+    fn from_value(format: ImportFormat, value: &Value) -> Result<Self, ImportError> {
+        // Applications commonly store the credentials next to the persistent IDs.
+        let root = object(value, "root")?;
+        let credentials = match root.get("credentials") {
+            Some(credentials) => object(credentials, "credentials")?,
+            None => root,
+        };
+        let keys = object(field(credentials, &["keys"])?, "keys")?;
+        let gcm = object(field(credentials, &["gcm"])?, "gcm")?;
+        let fcm = object(field(credentials, &["fcm"])?, "fcm")?;
+
+        let keys = match format {
+            ImportFormat::PushReceiver => Keys {
+                private_key: raw_private_key(&base64(keys, &["privateKey"])?)?,
+                public_key: base64(keys, &["publicKey"])?,
+                auth_secret: base64(keys, &["authSecret"])?.into(),
+            },
+            ImportFormat::PythonPushReceiver => Keys {
+                private_key: raw_private_key(&base64(keys, &["private"])?)?,
+                public_key: base64(keys, &["public"])?,
+                auth_secret: base64(keys, &["secret"])?.into(),
+            },
+        };
+        let gcm = GcmCredentials {
+            token: string(gcm, &["token"])?.into(),
+            android_id: string(gcm, &["androidId", "android_id"])?,
+            security_token: string(gcm, &["securityToken", "security_token"])?.into(),
+            app_id: string(gcm, &["appId", "app_id"])?,
+        };
+        let fcm = FcmCredentials {
+            token: string(fcm, &["token"])?.into(),
+            push_set: string(fcm, &["pushSet", "push_set"]).unwrap_or_default(),
+        };
+        let persistent_ids = match field(root, &["persistentIds", "persistent_ids"]) {
+            Ok(Value::Array(ids)) => ids
+                .iter()
+                .map(|id| {
+                    id.as_str()
+                        .map(Into::into)
+                        .ok_or(ImportError::invalid("persistentIds"))
+                })
+                .collect::<Result<_, _>>()?,
+            Ok(_) => return Err(ImportError::invalid("persistentIds")),
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Self {
+            credentials: Credentials {
+                keys: keys.base64_encode(),
+                gcm,
+                fcm,
+            },
+            persistent_ids,
+        })
+    }
+}
+
+/// Detects the format from the names of the key fields.
+fn detect(value: &Value) -> Option<ImportFormat> {
+    let credentials = value.get("credentials").unwrap_or(value);
+    let keys = credentials.get("keys")?;
+    if keys.get("privateKey").is_some() {
+        Some(ImportFormat::PushReceiver)
+    } else if keys.get("private").is_some() {
+        Some(ImportFormat::PythonPushReceiver)
+    } else {
+        None
+    }
+}
+
+fn object<'a>(value: &'a Value, name: &str) -> Result<&'a Map<String, Value>, ImportError> {
+    value.as_object().ok_or(ImportError::invalid(name))
+}
+
+/// Returns the first field present under any of `names`.
+fn field<'a>(object: &'a Map<String, Value>, names: &[&str]) -> Result<&'a Value, ImportError> {
+    names
+        .iter()
+        .find_map(|name| object.get(*name))
+        .ok_or(ImportError::missing(names[0]))
+}
+
+/// Returns a string field, accepting numbers for IDs written as JSON numbers.
+fn string(object: &Map<String, Value>, names: &[&str]) -> Result<String, ImportError> {
+    match field(object, names)? {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(value) => Ok(value.to_string()),
+        _ => Err(ImportError::invalid(names[0])),
+    }
+}
+
+/// Decodes a base64 field, accepting both the standard and URL-safe alphabets with or without
+/// padding.
+fn base64(object: &Map<String, Value>, names: &[&str]) -> Result<Vec<u8>, ImportError> {
+    let value = string(object, names)?;
+    let value: String = value
+        .chars()
+        .filter(|&c| c != '=')
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+
+    Ok(BASE64_URL_SAFE_NO_PAD.decode(value)?)
+}
+
+/// Converts a raw, PKCS#8 DER or SEC1 DER encoded P-256 private key to a raw private key.
+fn raw_private_key(key: &[u8]) -> Result<Secret<Vec<u8>>, ImportError> {
+    if key.len() == PRIVATE_KEY_LEN {
+        return Ok(key.to_vec().into());
+    }
+    let ec_key = match PKey::private_key_from_der(key) {
+        Ok(pkey) => pkey.ec_key()?,
+        Err(_) => EcKey::private_key_from_der(key)?,
+    };
+
+    Ok(ec_key
+        .private_key()
+        .to_vec_padded(PRIVATE_KEY_LEN as i32)?
+        .into())
+}
+
+impl Keys<Vec<u8>> {
+    pub(crate) fn base64_encode(&self) -> Keys<String> {
+        Keys {
+            private_key: BASE64_URL_SAFE_NO_PAD
+                .encode(self.private_key.expose())
+                .into(),
+            public_key: BASE64_URL_SAFE_NO_PAD.encode(&self.public_key),
+            auth_secret: BASE64_URL_SAFE_NO_PAD
+                .encode(self.auth_secret.expose())
+                .into(),
+        }
+    }
+}
+
+/// Errors returned when importing credentials.
+#[derive(Debug, Error)]
+#[error("failed to import credentials: {0}")]
+pub enum ImportError {
+    Io(#[from] std::io::Error),
+    Json(#[from] serde_json::Error),
+    Base64Decode(#[from] base64::DecodeError),
+    #[error("invalid private key: {0}")]
+    PrivateKey(#[from] openssl::error::ErrorStack),
+    #[error("unknown credentials format")]
+    UnknownFormat,
+    #[error("missing field `{field}`")]
+    MissingField {
+        field: String,
+    },
+    #[error("invalid field `{field}`")]
+    InvalidField {
+        field: String,
+    },
+}
+
+impl ImportError {
+    fn missing(field: impl Into<String>) -> Self {
+        Self::MissingField {
+            field: field.into(),
+        }
+    }
+
+    fn invalid(field: impl Into<String>) -> Self {
+        Self::InvalidField {
+            field: field.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::{BASE64_STANDARD, BASE64_URL_SAFE};
+    use openssl::bn::BigNumContext;
+    use openssl::ec::{EcGroup, PointConversionForm};
+    use openssl::nid::Nid;
+
+    use super::*;
+    use crate::webpush::{self, ContentEncoding};
+    use crate::Client;
+
+    /// Key material of another client: a P-256 key and an auth secret.
+    struct OtherKeys {
+        key: EcKey<openssl::pkey::Private>,
+        public_key: Vec<u8>,
+        auth_secret: [u8; 16],
+    }
+
+    fn other_keys() -> OtherKeys {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let public_key = key
+            .public_key()
+            .to_bytes(
+                &group,
+                PointConversionForm::UNCOMPRESSED,
+                &mut BigNumContext::new().unwrap(),
+            )
+            .unwrap();
+        let mut auth_secret = [0; 16];
+        openssl::rand::rand_bytes(&mut auth_secret).unwrap();
+
+        OtherKeys {
+            key,
+            public_key,
+            auth_secret,
+        }
+    }
+
+    /// Checks that the imported credentials are valid and decrypt messages sent to the other
+    /// client's keys.
+    fn assert_usable(imported: ImportedCredentials, keys: &OtherKeys) {
+        imported.credentials.validate().unwrap();
+        let message = webpush::encrypt(
+            &BASE64_URL_SAFE_NO_PAD.encode(&keys.public_key),
+            &BASE64_URL_SAFE_NO_PAD.encode(keys.auth_secret),
+            b"payload",
+            ContentEncoding::Aes128Gcm,
+        )
+        .unwrap();
+        let client = Client::new(imported.credentials).unwrap();
+
+        assert_eq!(client.decrypt(&message).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn imports_push_receiver() {
+        let keys = other_keys();
+        let json = serde_json::json!({
+            "credentials": {
+                "keys": {
+                    "privateKey": BASE64_STANDARD.encode(keys.key.private_key().to_vec_padded(32).unwrap()),
+                    "publicKey": BASE64_STANDARD.encode(&keys.public_key),
+                    "authSecret": BASE64_STANDARD.encode(keys.auth_secret),
+                },
+                "gcm": {
+                    "androidId": "4962787281391866131",
+                    "securityToken": "6573049572947230452",
+                    "appId": "wp:receiver.push.com#5b0ac6e2-1b5e-4b3b-a0e1-7d4a4d4f8b3c",
+                    "token": "cQ9kH3Vr1cE:APA91bGs-token",
+                },
+                "fcm": {
+                    "token": "dZ8mW1k2QkE:APA91bH-token",
+                    "pushSet": "eYvCkMt1s7w:APA91bF-push-set",
+                },
+            },
+            "persistentIds": ["0:1700000000000000%7031b2e6f9fd7ecd", "0:1700000000000001%7031b2e6f9fd7ecd"],
+        });
+
+        let imported = ImportedCredentials::parse(&json.to_string()).unwrap();
+
+        assert_eq!(
+            imported.persistent_ids,
+            [
+                "0:1700000000000000%7031b2e6f9fd7ecd",
+                "0:1700000000000001%7031b2e6f9fd7ecd"
+            ]
+        );
+        assert_usable(imported, &keys);
+    }
+
+    fn python_push_receiver(keys: &OtherKeys, private_key_der: &[u8]) -> String {
+        serde_json::json!({
+            "gcm": {
+                "token": "cQ9kH3Vr1cE:APA91bGs-token",
+                "androidId": 4962787281391866131u64,
+                "securityToken": 6573049572947230452u64,
+                "appId": "wp:receiver.push.com#5b0ac6e2-1b5e-4b3b-a0e1-7d4a4d4f8b3c",
+            },
+            "fcm": {
+                "token": "dZ8mW1k2QkE:APA91bH-token",
+                "pushSet": "eYvCkMt1s7w:APA91bF-push-set",
+            },
+            "keys": {
+                "public": BASE64_URL_SAFE.encode(&keys.public_key),
+                "private": BASE64_URL_SAFE.encode(private_key_der),
+                "secret": BASE64_URL_SAFE.encode(keys.auth_secret),
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn imports_python_push_receiver_pkcs8() {
+        let keys = other_keys();
+        let der = PKey::from_ec_key(keys.key.clone())
+            .unwrap()
+            .private_key_to_pkcs8()
+            .unwrap();
+
+        let imported = ImportedCredentials::parse(&python_push_receiver(&keys, &der)).unwrap();
+
+        assert!(imported.persistent_ids.is_empty());
+        assert_usable(imported, &keys);
+    }
+
+    #[test]
+    fn imports_python_push_receiver_sec1() {
+        let keys = other_keys();
+        let der = keys.key.private_key_to_der().unwrap();
+
+        let imported = ImportedCredentials::parse(&python_push_receiver(&keys, &der)).unwrap();
+
+        assert_usable(imported, &keys);
+    }
+}
@@ -6,37 +6,95 @@
 //! another alternative like [fcm-push-listener](https://crates.io/crates/fcm-push-listener).
 //!
 // https://chromium.googlesource.com/chromium/chromium/+/trunk/google_apis/gcm/
-use base64::DecodeError;
 use async_stream::stream;
-use base64::prelude::{Engine as _, BASE64_URL_SAFE};
-use ece::crypto::EcKeyComponents;
+use base64::DecodeError;
 use ece::legacy::AesGcmEncryptedBlock;
 use futures_util::Stream;
-use mcs::{DataMessageStanza, LoginRequest, Message, MissingDataError};
+use mcs::{
+    AsyncWriteExt as _, HeartbeatAck, HeartbeatPing, HeartbeatStat, LoginRequest, LoginResponse,
+    Message, MessageReader, MissingDataError, StreamIds,
+};
+use std::fmt;
+use std::time::SystemTime;
 use thiserror::Error;
 use tokio::net::TcpStream;
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
 use tokio_native_tls::{native_tls::TlsConnector as RawTlsConnector, TlsConnector, TlsStream};
 use uuid::Uuid;
+use vapid::{TrustedSenders, UntrustedAction};
+use webpush::{app_data, header_param, ContentEncoding};
+use zeroize::Zeroize as _;
 
-use prost::Message as _;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use mcs::AsyncReadExt as _;
-
+mod ack;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 mod credentials;
+mod dedup;
+mod encrypted;
+#[cfg(unix)]
+pub mod fanout;
 mod fcm;
 mod gcm;
+mod heartbeat;
+mod history;
+mod import;
 mod mcs;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod notification;
+mod receiver;
+mod secret;
+mod validate;
+pub mod vapid;
+pub mod webhook;
+pub mod webpush;
 
+use ack::Acks;
+use credentials::DecryptionKeys;
+use dedup::DedupCache;
 use gcm::GcmCredentials;
+use heartbeat::Heartbeat;
+use history::ConnectionHistory;
 
-pub use credentials::{Credentials, Keys};
+pub use ack::AckHandle;
+pub use credentials::{Credentials, Keys, StorageError};
+pub use heartbeat::HeartbeatState;
+pub use history::ConnectionEvent;
+pub use import::{ImportError, ImportFormat, ImportedCredentials};
+pub use mcs::{AppData, DataMessageStanza};
+pub use mcs::{Extension, IqExtension, IqStanza, SelectiveAck, StreamAck, StreamError};
+pub use notification::Notification;
+#[cfg(feature = "tracing")]
+pub use notification::NotificationSpan;
+pub use receiver::{Receiver, RecvError, Subscription};
+pub use secret::Secret;
+pub use validate::{LoginReport, ValidationError, ValidationIssue, VerifyReport};
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
 use reqwest::Client as Http;
 
+/// Logging macros, routed through `tracing` with the `tracing` feature so events are recorded in
+/// the current span. Without a tracing subscriber they are still emitted as `log` records.
+mod log {
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) use ::log::{debug, error, info, warn};
+    #[cfg(feature = "tracing")]
+    pub(crate) use tracing::{debug, error, info, warn};
+}
+
+/// Records a metric of a client if the `metrics` feature is enabled and metrics are set.
+macro_rules! record {
+    ($client:expr, $event:ident $(, $arg:expr)*) => {{
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &$client.metrics {
+            metrics.$event($($arg),*);
+        }
+    }};
+}
+
 const HOST: &str = "mtalk.google.com";
 // Hardcoded server-key from https://github.com/MatthieuLemoine/push-receiver
 // likely not possible to properly generate keys for this endpoint via the firebase endpoint
@@ -45,19 +103,88 @@ const SERVER_KEY: &str =
     "BDOU99-h67HcA6JeFXHbSNMu7e2yNNu3RzoMj8TM4W88jITfq7ZmPvIM1Iv-4_l2LxQcYwhqby2xGpWwzjfAnG4";
 const PORT: usize = 5228;
 const MCS_VERSION: u8 = 41;
+/// Time to wait for the login response in [`Client::verify()`].
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Client for receiving FCM push notifications.
-#[derive(Debug)]
 pub struct Client {
-    /// Persistent IDs of received messages.
+    /// Persistent IDs of received messages, acknowledged on the next login.
     pub persistent_ids: Vec<String>,
-    auth_secret: Vec<u8>,
-    ec_components: EcKeyComponents,
+    /// Persistent IDs of delivered messages, kept across reconnects.
+    dedup: DedupCache,
+    keys: DecryptionKeys,
+    /// Keys replaced by [`Client::rotate_keys()`] and the instant they expire at.
+    previous_keys: Option<(DecryptionKeys, Instant)>,
     gcm_credentials: GcmCredentials,
     connect_retry_timeout_max: Duration,
+    max_frame_size: usize,
+    iq_handler: Option<Handler<IqStanza>>,
+    adaptive_heartbeat: bool,
+    heartbeat_state: HeartbeatState,
+    heartbeat_state_handler: Option<Handler<HeartbeatState>>,
+    heartbeat_stat: Option<HeartbeatStat>,
+    /// Local ip address of the latest connection.
+    network: String,
+    connection_history: ConnectionHistory,
+    /// Highest rmq id received from the server.
+    last_rmq_id: Option<i64>,
+    trusted_senders: Option<TrustedSenders>,
+    /// Registrations sharing the device of the primary registration.
+    registrations: Vec<AppRegistration>,
+    /// Acknowledgements of notifications, if enabled.
+    acks: Option<Acks>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<metrics::Metrics>,
     http: reqwest::Client,
 }
 
+/// Stream of a connection to FCM.
+#[cfg(feature = "metrics")]
+type McsStream = metrics::Counted<TlsStream<TcpStream>>;
+#[cfg(not(feature = "metrics"))]
+type McsStream = TlsStream<TcpStream>;
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("persistent_ids", &self.persistent_ids)
+            .field("gcm_credentials", &self.gcm_credentials)
+            .field("network", &self.network)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Additional registration of a [`Client`], with its own keys.
+struct AppRegistration {
+    app_id: String,
+    token: Secret<String>,
+    keys: DecryptionKeys,
+}
+
+/// Callback registered on the [`Client`], e.g. for IQ stanzas it does not handle itself.
+///
+/// Wrapped in a mutex so the client stays `Sync`, it is only accessed through `&mut`.
+struct Handler<T>(std::sync::Mutex<Callback<T>>);
+
+type Callback<T> = Box<dyn FnMut(&T) + Send>;
+
+impl<T> Handler<T> {
+    fn new(handler: impl FnMut(&T) + Send + 'static) -> Self {
+        Self(std::sync::Mutex::new(Box::new(handler)))
+    }
+
+    fn call(&mut self, value: &T) {
+        let handler = self.0.get_mut().unwrap_or_else(|error| error.into_inner());
+        handler(value)
+    }
+}
+
+impl<T> fmt::Debug for Handler<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Handler")
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
     #[error(transparent)]
@@ -71,50 +198,250 @@ pub enum ClientError {
     #[error(transparent)]
     FcmRegister(#[from] fcm::RegisterError),
     #[error(transparent)]
+    Stream(#[from] StreamError),
+    #[error(transparent)]
+    Read(#[from] mcs::ReadError),
+    #[error("mcs login rejected with code {code}: {message}")]
+    LoginRejected { code: i32, message: String },
+    #[error(transparent)]
     Base64Decode(#[from] base64::DecodeError),
     #[error("network error: {0}")]
     Network(#[from] std::io::Error),
     #[error(transparent)]
     Tls(#[from] tokio_native_tls::native_tls::Error),
+    #[error("registration belongs to another device (android id {android_id})")]
+    ForeignRegistration { android_id: String },
 }
 
 impl Client {
     /// Constructs the client.
     pub fn new(credentials: Credentials) -> Result<Self, ClientError> {
-        let keys = credentials.keys.base64_decode()?;
+        let keys = DecryptionKeys::new(&credentials.keys)?;
         let gcm_credentials = credentials.gcm;
-        let ec_components = EcKeyComponents::new(keys.private_key, keys.public_key);
 
         Ok(Self {
-            auth_secret: keys.auth_secret,
-            ec_components,
+            keys,
+            previous_keys: None,
             gcm_credentials,
             persistent_ids: Default::default(),
+            dedup: DedupCache::new(dedup::DEFAULT_CAPACITY, dedup::DEFAULT_MAX_TTL),
             connect_retry_timeout_max: Duration::from_secs(80),
+            max_frame_size: mcs::DEFAULT_MAX_FRAME_SIZE,
+            iq_handler: None,
+            adaptive_heartbeat: false,
+            heartbeat_state: Default::default(),
+            heartbeat_state_handler: None,
+            heartbeat_stat: None,
+            network: Default::default(),
+            connection_history: Default::default(),
+            last_rmq_id: None,
+            trusted_senders: None,
+            registrations: Vec::new(),
+            acks: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
             http: reqwest::Client::new(),
         })
     }
 
+    /// Sets the largest MCS frame, in bytes, the client accepts before dropping the connection.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Sets a handler called for IQ stanzas the client does not handle itself.
+    pub fn with_iq_handler(mut self, handler: impl FnMut(&IqStanza) + Send + 'static) -> Self {
+        self.iq_handler = Some(Handler::new(handler));
+        self
+    }
+
+    /// Only trusts messages from the given senders.
+    ///
+    /// Messages from other senders are dropped or flagged depending on
+    /// [`TrustedSenders::action()`].
+    pub fn with_trusted_senders(mut self, senders: TrustedSenders) -> Self {
+        self.trusted_senders = Some(senders);
+        self
+    }
+
+    /// Requires notifications to be acknowledged with their [`Notification::ack`] handle.
+    ///
+    /// Acknowledged notifications are reported to FCM with a selective ack right away and again
+    /// on the next login. Notifications that are not acknowledged, e.g. because the process
+    /// crashed while handling them, are redelivered after a reconnect, giving at-least-once
+    /// delivery.
+    pub fn with_manual_ack(mut self) -> Self {
+        self.acks = Some(Acks::new());
+        self
+    }
+
+    /// Sets how many persistent IDs are remembered to drop redelivered messages, and for how
+    /// long at most.
+    ///
+    /// IDs are remembered until the TTL of their message has passed, capped at `max_ttl`, or until
+    /// more than `capacity` newer IDs are remembered. Defaults to 10000 IDs for up to 28 days, the
+    /// longest TTL FCM supports.
+    pub fn with_dedup(mut self, capacity: usize, max_ttl: Duration) -> Self {
+        self.dedup = DedupCache::new(capacity, max_ttl);
+        self
+    }
+
+    /// Records connection and message metrics in `metrics`.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: metrics::Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Enables adaptive heartbeats.
+    ///
+    /// Adaptive heartbeats probe for the longest interval the network tolerates, starting from the
+    /// interval learned in `state`, and report the outcome to the server on the next login.
+    pub fn with_adaptive_heartbeat(mut self, state: HeartbeatState) -> Self {
+        self.adaptive_heartbeat = true;
+        self.heartbeat_state = state;
+        self
+    }
+
+    /// Sets a handler called whenever adaptive heartbeats learn a new interval, e.g. to persist
+    /// it with [`Credentials::save_heartbeat_state()`].
+    pub fn with_heartbeat_state_handler(
+        mut self,
+        handler: impl FnMut(&HeartbeatState) + Send + 'static,
+    ) -> Self {
+        self.heartbeat_state_handler = Some(Handler::new(handler));
+        self
+    }
+
+    /// Returns the heartbeat intervals learned by adaptive heartbeats, for persisting.
+    pub fn heartbeat_state(&self) -> &HeartbeatState {
+        &self.heartbeat_state
+    }
+
+    /// Returns the recorded connection attempts, oldest first.
+    pub fn connection_history(&self) -> impl ExactSizeIterator<Item = &ConnectionEvent> {
+        self.connection_history.events()
+    }
+
+    /// Accepts `keys` replaced by a key rotation for decrypting messages until `grace_period`
+    /// has passed.
+    ///
+    /// This is useful when the client is restarted during the grace period of
+    /// [`Client::rotate_keys()`].
+    pub fn with_previous_keys(
+        mut self,
+        keys: &Keys<String>,
+        grace_period: Duration,
+    ) -> Result<Self, ClientError> {
+        let keys = DecryptionKeys::new(keys)?;
+        self.previous_keys = Some((keys, Instant::now() + grace_period));
+        Ok(self)
+    }
+
+    /// Generates new encryption keys and subscribes them with FCM, reusing the existing GCM
+    /// registration.
+    ///
+    /// Messages encrypted with the previous keys are still decrypted until `grace_period` has
+    /// passed. Returns the updated [`Credentials`], which replace the previously stored ones.
+    pub async fn rotate_keys(
+        &mut self,
+        sender_id: impl Into<String>,
+        grace_period: Duration,
+    ) -> Result<Credentials, ClientError> {
+        let registration = fcm::register(sender_id, self.gcm_credentials.token.expose()).await?;
+        let keys = DecryptionKeys::new(&registration.keys)?;
+        let previous_keys = std::mem::replace(&mut self.keys, keys);
+        self.previous_keys = Some((previous_keys, Instant::now() + grace_period));
+        log::info!("fcm encryption keys rotated");
+
+        Ok(Credentials {
+            keys: registration.keys,
+            gcm: self.gcm_credentials.clone(),
+            fcm: registration.fcm,
+        })
+    }
+
+    /// Adds a registration of the same device, e.g. for another sender ID.
+    ///
+    /// Messages are routed to the registration by their app ID or registration token and
+    /// decrypted with its keys.
+    pub fn with_registration(mut self, credentials: &Credentials) -> Result<Self, ClientError> {
+        if credentials.gcm.android_id != self.gcm_credentials.android_id {
+            return Err(ClientError::ForeignRegistration {
+                android_id: credentials.gcm.android_id.clone(),
+            });
+        }
+        self.registrations.push(AppRegistration {
+            app_id: credentials.gcm.app_id.clone(),
+            token: credentials.gcm.token.clone(),
+            keys: DecryptionKeys::new(&credentials.keys)?,
+        });
+        Ok(self)
+    }
+
+    /// Registers another app for `sender_id` on the device of the client and adds it to the
+    /// client.
+    ///
+    /// Returns [`Credentials`] for restoring the registration with
+    /// [`Client::with_registration()`].
+    pub async fn add_registration(
+        &mut self,
+        sender_id: impl Into<String>,
+    ) -> Result<Credentials, ClientError> {
+        self.add_registration_with(sender_id, SERVER_KEY).await
+    }
+
+    /// Registers another app for `sender_id` on the device of the client and adds it to the
+    /// client.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "register", skip_all, err)
+    )]
+    pub async fn add_registration_with(
+        &mut self,
+        sender_id: impl Into<String>,
+        server_key: impl AsRef<str>,
+    ) -> Result<Credentials, ClientError> {
+        let gcm_credentials = gcm::register_app(
+            &self.http,
+            self.gcm_credentials.android_id.clone(),
+            self.gcm_credentials.security_token.expose().clone(),
+            new_app_id(),
+            server_key,
+        )
+        .await?;
+        let registration = fcm::register(sender_id, gcm_credentials.token.expose()).await?;
+        self.registrations.push(AppRegistration {
+            app_id: gcm_credentials.app_id.clone(),
+            token: gcm_credentials.token.clone(),
+            keys: DecryptionKeys::new(&registration.keys)?,
+        });
+
+        Ok(Credentials {
+            keys: registration.keys,
+            gcm: gcm_credentials,
+            fcm: registration.fcm,
+        })
+    }
+
     /// Registers the client with FCM and returns [`Credentials`] for the [`Client`].
     pub async fn register(sender_id: impl Into<String>) -> Result<Credentials, ClientError> {
         Self::register_with(sender_id, SERVER_KEY).await
     }
 
     /// Registers the client with FCM and returns [`Credentials`] for the [`Client`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "register", skip_all, err)
+    )]
     pub async fn register_with(
         sender_id: impl Into<String>,
         server_key: impl AsRef<str>,
     ) -> Result<Credentials, ClientError> {
         let http = Http::new();
-
-        let mut uuid_buffer = Uuid::encode_buffer();
-        let uuid = Uuid::new_v4()
-            .as_hyphenated()
-            .encode_lower(&mut uuid_buffer);
-
-        let app_id = format!("wp:receiver.push.com#{uuid}");
-        let gcm_credentials = gcm::register(&http, app_id, server_key).await?;
-        let registration = fcm::register(sender_id, &gcm_credentials.token).await?;
+        let gcm_credentials = gcm::register(&http, new_app_id(), server_key).await?;
+        let registration = fcm::register(sender_id, gcm_credentials.token.expose()).await?;
 
         log::debug!("{registration:#?}");
 
@@ -126,27 +453,105 @@ impl Client {
     }
 
     /// Returns a stream that yields FCM notifications.
-    pub fn notifications(&mut self) -> impl Stream<Item = Result<Vec<u8>, ClientError>> + '_ {
+    ///
+    /// Web push encrypted messages are decrypted, plain data messages are delivered as is. Messages
+    /// that fail to decrypt are yielded as errors and acknowledged, the stream continues with the
+    /// next message.
+    pub fn notifications(&mut self) -> impl Stream<Item = Result<Notification, ClientError>> + '_ {
         stream! {loop {
-            let mut stream = self.connect().await;
+            let stream = self.connect().await;
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut reader = MessageReader::new(reader, self.max_frame_size);
+            let mut stream_ids = StreamIds::new();
+            let mut heartbeat = Heartbeat::new(&self.network, self.adaptive_heartbeat, &self.heartbeat_state);
             log::info!("fcm connected");
             loop {
-                match stream.read_message().await {
+                let result = tokio::select! {
+                    // Frames that arrived while the consumer held a notification, such as a
+                    // heartbeat ack, are handled before the heartbeat deadline is checked.
+                    biased;
+                    result = reader.read_message() => result,
+                    _ = time::sleep_until(heartbeat.deadline()) => {
+                        if heartbeat.awaiting_ack() {
+                            log::warn!("fcm heartbeat timed out");
+                            self.heartbeat_stat = self.update_heartbeat_state(|state| heartbeat.on_timeout(state));
+                            break;
+                        }
+                        let ping = HeartbeatPing {
+                            last_stream_id_received: stream_ids.last_received.into(),
+                            ..Default::default()
+                        };
+                        if let Err(error) = writer.write_message(&ping).await {
+                            log::error!("{error:#?}");
+                            break;
+                        }
+                        stream_ids.on_sent();
+                        heartbeat.on_ping_sent();
+                        continue;
+                    }
+                    persistent_ids = recv_ack(&mut self.acks) => {
+                        // Also reported on the next login in case the ack gets lost.
+                        self.persistent_ids.extend(persistent_ids.iter().cloned());
+                        let ack = IqStanza::selective_ack(stream_ids.last_received, persistent_ids);
+                        if let Err(error) = writer.write_message(&ack).await {
+                            log::error!("{error:#?}");
+                            break;
+                        }
+                        stream_ids.on_sent();
+                        continue;
+                    }
+                };
+                match result {
                     Ok(message) => {
                         log::debug!("{message:#?}");
+                        stream_ids.on_received();
+                        heartbeat.on_activity();
                         match message {
-                            Message::DataMessageStanza(message) => {
-                                let persistent_id = message.persistent_id().to_string();
-                                if !self.persistent_ids.contains(&persistent_id) {
-                                    self.persistent_ids.push(message.persistent_id().to_string());
-                                    yield Ok(self.decrypt(message)?);
+                            Message::DataMessageStanza(message) => match self.on_data_message(message) {
+                                Some(result) => yield result,
+                                None => (),
+                            },
+                            Message::LoginResponse(response) => {
+                                self.on_login_response(&response);
+                                if let Some(config) = &response.heartbeat_config {
+                                    heartbeat.configure(config);
                                 }
                             },
-                            Message::LoginResponse(_) => {
-                                self.persistent_ids = Vec::new();
+                            Message::HeartbeatAck(_) => {
+                                #[cfg(feature = "metrics")]
+                                if let (Some(metrics), Some(round_trip)) = (&self.metrics, heartbeat.round_trip()) {
+                                    metrics.on_heartbeat_ack(round_trip);
+                                }
+                                if let Some(stat) = self.update_heartbeat_state(|state| heartbeat.on_ack(state)) {
+                                    self.heartbeat_stat = Some(stat);
+                                }
                             },
+                            Message::StreamErrorStanza(error) => {
+                                yield Err(StreamError::from(error).into());
+                                break;
+                            },
+                            Message::HeartbeatPing(_) => {
+                                let ack = HeartbeatAck {
+                                    last_stream_id_received: stream_ids.last_received.into(),
+                                    ..Default::default()
+                                };
+                                if let Err(error) = writer.write_message(&ack).await {
+                                    log::error!("{error:#?}");
+                                    break;
+                                }
+                                stream_ids.on_sent();
+                            },
+                            Message::IqStanza(stanza) => self.handle_iq(stanza),
                             _ => ()
                         }
+                        if stream_ids.needs_stream_ack() {
+                            let ack = IqStanza::stream_ack(stream_ids.last_received);
+                            if let Err(error) = writer.write_message(&ack).await {
+                                log::error!("{error:#?}");
+                                break;
+                            }
+                            stream_ids.on_sent();
+                        }
                     }
                     Err(error) => {
                         log::error!("{error:#?}");
@@ -154,49 +559,64 @@ impl Client {
                     }
                 }
             }
+            self.connection_history.on_disconnected();
         }}
     }
 
     /// Repeatedly attempts to connect to FCM until succeeded, returning a raw stream.
-    pub(crate) async fn connect(&mut self) -> TlsStream<TcpStream> {
-        match self.try_connect().await {
-            Ok(stream) => return stream,
-            Err(error) => log::debug!("{error:?}"),
-        }
-        let mut retry_attempt = 1;
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub(crate) async fn connect(&mut self) -> McsStream {
+        let mut retry_attempt = 0;
         let mut retry_timeout = Duration::from_secs(5);
         loop {
+            let started_at = SystemTime::now();
+            match self.try_connect().await {
+                Ok(stream) => {
+                    self.connection_history.on_connected(started_at);
+                    record!(self, on_connected);
+                    return stream;
+                }
+                Err(error) => {
+                    log::debug!("{error:?}");
+                    self.connection_history.on_failed(started_at, &error);
+                }
+            }
+            retry_attempt += 1;
+            record!(self, on_reconnect_attempt);
             log::warn!(
                 "fcm connection failed, trying again in {} seconds (attempt {})",
                 retry_timeout.as_secs(),
                 retry_attempt
             );
             time::sleep(retry_timeout).await;
-            match self.try_connect().await {
-                Ok(stream) => return stream,
-                Err(error) => log::debug!("{error:?}"),
-            }
-            retry_attempt += 1;
             retry_timeout = self.connect_retry_timeout_max.min(retry_timeout * 2);
         }
     }
 
     /// Attempts to connect to FCM, returning a raw stream.
-    pub(crate) async fn try_connect(&mut self) -> Result<TlsStream<TcpStream>, ClientError> {
+    pub(crate) async fn try_connect(&mut self) -> Result<McsStream, ClientError> {
         self.check_in().await?;
+        let stream = self.login().await?;
+        self.connection_history.on_login_request();
+        Ok(stream)
+    }
 
+    /// Opens a raw stream to FCM and sends the login request.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, err))]
+    async fn login(&mut self) -> Result<McsStream, ClientError> {
         // Init stream
         let address = format!("{HOST}:{PORT}");
         let tcp_stream = TcpStream::connect(address).await?;
+        self.network = tcp_stream.local_addr()?.ip().to_string();
         let connector = TlsConnector::from(RawTlsConnector::new()?);
-        let mut stream = connector.connect(HOST, tcp_stream).await?;
+        let mut stream = self.counted(connector.connect(HOST, tcp_stream).await?);
         stream.write_u8(MCS_VERSION).await?;
 
         // Login
-        stream.write_i8(LoginRequest::TAG).await?;
-        let buf = self.login_request()?.encode_length_delimited_to_vec();
-
-        stream.write_all(&buf).await?;
+        let mut login_request = self.login_request()?;
+        let result = stream.write_message(&login_request).await;
+        login_request.auth_token.zeroize();
+        result?;
 
         let mcs_version = stream.read_u8().await?;
         if mcs_version != MCS_VERSION {
@@ -206,22 +626,246 @@ impl Client {
         Ok(stream)
     }
 
-    fn decrypt(&self, msg: DataMessageStanza) -> Result<Vec<u8>, DecryptError> {
-        let crypto_key = &msg.app_data("crypo-key")?.value[3..];
-        let salt = &msg.app_data("encryption")?.value[5..];
+    /// Wraps the stream of a connection to count its bytes in the metrics, if enabled.
+    fn counted(&self, stream: TlsStream<TcpStream>) -> McsStream {
+        #[cfg(feature = "metrics")]
+        return metrics::Counted::new(stream, self.metrics.clone());
+        #[cfg(not(feature = "metrics"))]
+        stream
+    }
+
+    /// Checks the client against FCM without receiving notifications.
+    ///
+    /// Performs a GCM check-in and an MCS login, waiting for the login response before closing
+    /// the connection.
+    pub async fn verify(&mut self) -> VerifyReport {
+        let started_at = Instant::now();
+        let check_in = self.check_in().await.map(|()| started_at.elapsed());
+        let login = match check_in {
+            Ok(_) => Some(self.verify_login().await),
+            Err(_) => None,
+        };
+
+        VerifyReport { check_in, login }
+    }
+
+    async fn verify_login(&mut self) -> Result<LoginReport, ClientError> {
+        let started_at = Instant::now();
+        let stream = self.login().await?;
+        let mut reader = MessageReader::new(stream, self.max_frame_size);
+        let deadline = started_at + LOGIN_TIMEOUT;
+
+        let response = loop {
+            match time::timeout_at(deadline, reader.read_message()).await {
+                Ok(Ok(Message::LoginResponse(response))) => break response,
+                Ok(Ok(message)) => {
+                    log::debug!("ignoring message before login response: {message:?}")
+                }
+                Ok(Err(error)) => return Err(error.into()),
+                Err(_) => return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+            }
+        };
+        if let Some(error) = response.error {
+            return Err(ClientError::LoginRejected {
+                code: error.code,
+                message: error.message.unwrap_or_default(),
+            });
+        }
+
+        Ok(LoginReport {
+            duration: started_at.elapsed(),
+            server_timestamp: response.server_timestamp,
+            heartbeat_interval: response
+                .heartbeat_config
+                .and_then(|config| config.interval_ms)
+                .map(|interval_ms| Duration::from_millis(interval_ms as u64)),
+        })
+    }
+
+    /// Handles the response to the login request of a new connection.
+    fn on_login_response(&mut self, response: &LoginResponse) {
+        // Reported in the login request.
+        self.persistent_ids = Vec::new();
+        self.heartbeat_stat = None;
+        // FCM redelivers unacknowledged messages on the new connection.
+        if let Some(acks) = &mut self.acks {
+            for persistent_id in acks.take_pending() {
+                self.dedup.remove(&persistent_id);
+            }
+        }
+        if response.error.is_none() {
+            self.connection_history.on_login();
+        } else {
+            record!(self, on_login_failed);
+        }
+    }
+
+    /// Handles a data message, returning the notification to deliver unless the message is a
+    /// duplicate or rejected.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "message",
+            skip_all,
+            fields(
+                persistent_id = message.persistent_id(),
+                from = %message.from,
+                category = %message.category,
+            ),
+        )
+    )]
+    fn on_data_message(
+        &mut self,
+        message: DataMessageStanza,
+    ) -> Option<Result<Notification, ClientError>> {
+        let persistent_id = message.persistent_id().to_string();
+        record!(self, on_message);
+        if self.dedup.contains(&persistent_id) {
+            record!(self, on_duplicate);
+            return None;
+        }
+        self.dedup.insert(&message);
+        let sender = self
+            .trusted_senders
+            .as_ref()
+            .map(|senders| (senders.is_trusted(&message), senders.action()));
+        let rejected = matches!(sender, Some((false, UntrustedAction::Reject)));
+        let ack = match (&mut self.acks, rejected) {
+            (Some(acks), false) => Some(acks.handle(persistent_id.clone())),
+            _ => {
+                self.persistent_ids.push(persistent_id.clone());
+                None
+            }
+        };
+        if rejected {
+            log::warn!(
+                "rejected message {persistent_id} from untrusted sender `{}`",
+                message.from
+            );
+            return None;
+        }
+
+        let payload = match message.is_encrypted() {
+            true => match self.decrypt(&message) {
+                Ok(payload) => Some(payload),
+                Err(error) => {
+                    record!(self, on_decrypt_failed);
+                    // A redelivery would fail the same way, acknowledge the message right away.
+                    if let Some(ack) = ack {
+                        ack.ack();
+                    }
+                    return Some(Err(error.into()));
+                }
+            },
+            false => None,
+        };
+        let app_id = match self.route(&message) {
+            Some(registration) => registration.app_id.clone(),
+            None => self.gcm_credentials.app_id.clone(),
+        };
+        let mut notification = Notification::new(message, payload);
+        notification.app_id = app_id;
+        notification.trusted = sender.map(|(trusted, _)| trusted);
+        notification.ack = ack;
+        Some(Ok(notification))
+    }
+
+    /// Applies `update` to the learned heartbeat intervals, passing them to the handler if the
+    /// interval of the current network changed.
+    fn update_heartbeat_state<T>(&mut self, update: impl FnOnce(&mut HeartbeatState) -> T) -> T {
+        let learned = |state: &HeartbeatState, network: &str| {
+            (state.interval(network), state.ceiling(network))
+        };
+        let previous = learned(&self.heartbeat_state, &self.network);
+        let result = update(&mut self.heartbeat_state);
+        if learned(&self.heartbeat_state, &self.network) != previous {
+            if let Some(handler) = &mut self.heartbeat_state_handler {
+                handler.call(&self.heartbeat_state);
+            }
+        }
+        result
+    }
+
+    /// Handles an IQ stanza sent by the server.
+    fn handle_iq(&mut self, stanza: IqStanza) {
+        if stanza.rmq_id.is_some() {
+            self.last_rmq_id = self.last_rmq_id.max(stanza.rmq_id);
+        }
+        match stanza.decode_extension() {
+            Ok(Some(IqExtension::SelectiveAck(ack))) => {
+                log::debug!("server acknowledged messages: {:?}", ack.id);
+            }
+            Ok(Some(IqExtension::StreamAck(_))) => {
+                log::debug!("server acknowledged stream");
+            }
+            Ok(_) => match &mut self.iq_handler {
+                Some(handler) => handler.call(&stanza),
+                None => log::debug!("unhandled iq stanza with id `{}`", stanza.id),
+            },
+            Err(error) => log::warn!("failed to decode iq extension: {error}"),
+        }
+    }
+
+    /// Decrypts the payload of a web push message.
+    ///
+    /// Both the `aesgcm` and `aes128gcm` content encodings are supported.
+    pub fn decrypt(&self, message: &DataMessageStanza) -> Result<Vec<u8>, DecryptError> {
+        let ciphertext = message.raw_data();
+        let encoding = message
+            .app_data(app_data::CONTENT_ENCODING)
+            .map(|data| data.value.as_str());
+        if encoding.is_ok_and(|encoding| encoding == ContentEncoding::Aes128Gcm.as_str()) {
+            return Ok(self.decrypt_with_keys(message, |keys| {
+                ece::decrypt(&keys.ec_components(), keys.auth_secret.expose(), ciphertext)
+            })?);
+        }
+
+        let crypto_key = &message.app_data(app_data::CRYPTO_KEY)?.value;
+        let encryption = &message.app_data(app_data::ENCRYPTION)?.value;
+        let dh = header_param(crypto_key, "dh").ok_or(DecryptError::MissingParameter("dh"))?;
+        let salt =
+            header_param(encryption, "salt").ok_or(DecryptError::MissingParameter("salt"))?;
 
-        let auth = &self.auth_secret;
-        let dh = BASE64_URL_SAFE.decode(crypto_key)?;
-        let ciphertext = msg.raw_data();
+        let dh = webpush::decode_base64(dh)?;
         let rs = ciphertext.len() as u32;
-        let salt = BASE64_URL_SAFE.decode(salt)?;
+        let salt = webpush::decode_base64(salt)?;
 
         let data = AesGcmEncryptedBlock::new(&dh, &salt, rs, ciphertext.to_vec())?;
-        Ok(ece::legacy::decrypt_aesgcm(
-            &self.ec_components,
-            auth,
-            &data,
-        )?)
+        Ok(self.decrypt_with_keys(message, |keys| {
+            ece::legacy::decrypt_aesgcm(&keys.ec_components(), keys.auth_secret.expose(), &data)
+        })?)
+    }
+
+    /// Returns the additional registration a message is addressed to, `None` for the primary
+    /// registration.
+    fn route(&self, message: &DataMessageStanza) -> Option<&AppRegistration> {
+        let app_id = message.app_id();
+        let to = message.to();
+        self.registrations.iter().find(|registration| {
+            registration.app_id == app_id || (!to.is_empty() && registration.token.expose() == to)
+        })
+    }
+
+    /// Decrypts with the keys of the registration the message is addressed to.
+    ///
+    /// Messages for the primary registration fall back to the previous keys during their grace
+    /// period.
+    fn decrypt_with_keys(
+        &self,
+        message: &DataMessageStanza,
+        decrypt: impl Fn(&DecryptionKeys) -> Result<Vec<u8>, ece::Error>,
+    ) -> Result<Vec<u8>, ece::Error> {
+        if let Some(registration) = self.route(message) {
+            return decrypt(&registration.keys);
+        }
+        let result = decrypt(&self.keys);
+        match &self.previous_keys {
+            Some((keys, expires_at)) if result.is_err() && Instant::now() < *expires_at => {
+                log::debug!("decrypting with previous keys");
+                decrypt(keys)
+            }
+            _ => result,
+        }
     }
 
     async fn check_in(&self) -> Result<(), ClientError> {
@@ -230,15 +874,19 @@ impl Client {
         let response = gcm::check_in(
             &self.http,
             android_id.parse::<u64>().ok(),
-            security_token.parse::<u64>().ok(),
+            security_token.expose().parse::<u64>().ok(),
         )
         .await?;
 
-        log::debug!("gcm check-in response: {response:#?}");
+        log::debug!(
+            "gcm check-in succeeded (stats ok: {}, server time: {})",
+            response.stats_ok,
+            response.time_msec()
+        );
         Ok(())
     }
 
-    fn login_request(&mut self) -> Result<LoginRequest, LoginRequestError> {
+    fn login_request(&self) -> Result<LoginRequest, LoginRequestError> {
         let android_id = &self.gcm_credentials.android_id;
         let android_id = android_id
             .parse::<i64>()
@@ -246,9 +894,10 @@ impl Client {
         let device_id = format!("android-{android_id:x}");
 
         Ok(LoginRequest {
-            adaptive_heartbeat: false.into(),
+            adaptive_heartbeat: self.adaptive_heartbeat.into(),
+            heartbeat_stat: self.heartbeat_stat.clone(),
             auth_service: 2.into(),
-            auth_token: self.gcm_credentials.security_token.clone(),
+            auth_token: self.gcm_credentials.security_token.expose().clone(),
             id: "chrome-63.0.3234.0".into(),
             domain: "mcs.android.com".into(),
             device_id: device_id.into(),
@@ -260,7 +909,11 @@ impl Client {
                 name: "new_vc".into(),
                 value: "1".into(),
             }],
-            received_persistent_id: std::mem::take(&mut self.persistent_ids),
+            received_persistent_id: self.persistent_ids.clone(),
+            last_rmq_id: self.last_rmq_id,
+            // Bit 0 is the idle flag, the receiver is always active.
+            status: 0.into(),
+            client_event: self.connection_history.client_events(),
             ..Default::default()
         })
     }
@@ -287,4 +940,125 @@ pub enum DecryptError {
     MissingData(#[from] MissingDataError),
     Ece(#[from] ece::Error),
     Base64Decode(#[from] DecodeError),
+    #[error("missing `{0}` parameter")]
+    MissingParameter(&'static str),
+}
+
+/// Generates a web push app ID.
+fn new_app_id() -> String {
+    let mut uuid_buffer = Uuid::encode_buffer();
+    let uuid = Uuid::new_v4()
+        .as_hyphenated()
+        .encode_lower(&mut uuid_buffer);
+
+    format!("wp:receiver.push.com#{uuid}")
+}
+
+/// Waits for acknowledged persistent IDs, forever if acknowledgements are disabled.
+async fn recv_ack(acks: &mut Option<Acks>) -> Vec<String> {
+    match acks {
+        Some(acks) => acks.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a client for test credentials and a message encrypted for it.
+    fn client_and_message() -> (Client, DataMessageStanza) {
+        let credentials = Credentials::for_test();
+        let keys = &credentials.keys;
+        let message = webpush::encrypt(
+            &keys.public_key,
+            keys.auth_secret.expose(),
+            b"payload",
+            ContentEncoding::Aes128Gcm,
+        )
+        .unwrap();
+        (Client::new(credentials).unwrap(), message)
+    }
+
+    fn corrupt(mut message: DataMessageStanza) -> DataMessageStanza {
+        message.persistent_id = Some("0:corrupt".into());
+        if let Some(byte) = message.raw_data.as_mut().and_then(|data| data.last_mut()) {
+            *byte ^= 1;
+        }
+        message
+    }
+
+    #[test]
+    fn delivers_message_after_corrupt_message() {
+        let (mut client, message) = client_and_message();
+
+        let error = client.on_data_message(corrupt(message.clone()));
+        let notification = client.on_data_message(message);
+
+        assert!(matches!(error, Some(Err(ClientError::Decrypt(_)))));
+        assert_eq!(notification.unwrap().unwrap().payload, b"payload");
+        // Both are acknowledged on the next login.
+        assert_eq!(client.persistent_ids.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn acknowledges_corrupt_message_with_manual_ack() {
+        let (client, message) = client_and_message();
+        let mut client = client.with_manual_ack();
+
+        let error = client.on_data_message(corrupt(message));
+
+        assert!(matches!(error, Some(Err(ClientError::Decrypt(_)))));
+        let acked = recv_ack(&mut client.acks).await;
+        assert_eq!(acked, ["0:corrupt"]);
+    }
+
+    #[tokio::test]
+    async fn redelivers_unacknowledged_notification() {
+        let (client, message) = client_and_message();
+        let mut client = client.with_manual_ack();
+        let mut acked = message.clone();
+        acked.persistent_id = Some("0:acked".into());
+
+        let first = client.on_data_message(message.clone()).unwrap().unwrap();
+        let ack = client.on_data_message(acked.clone()).unwrap().unwrap().ack;
+        ack.unwrap().ack();
+        assert_eq!(recv_ack(&mut client.acks).await, ["0:acked"]);
+        // Duplicates on the same connection are dropped either way.
+        assert!(client.on_data_message(message.clone()).is_none());
+
+        client.on_login_response(&LoginResponse::default());
+
+        let redelivered = client.on_data_message(message).unwrap().unwrap();
+        assert_eq!(redelivered.persistent_id, first.persistent_id);
+        assert!(redelivered.ack.is_some());
+        assert!(client.on_data_message(acked).is_none());
+    }
+
+    #[test]
+    fn passes_unhandled_iq_stanzas_to_handler() {
+        let (client, _) = client_and_message();
+        let handled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut client = client.with_iq_handler({
+            let handled = handled.clone();
+            move |stanza| handled.lock().unwrap().push(stanza.id.clone())
+        });
+        let unknown = IqStanza {
+            id: "unknown".into(),
+            extension: Some(Extension {
+                id: 99,
+                data: Vec::new(),
+            }),
+            rmq_id: Some(5),
+            ..Default::default()
+        };
+        let mut ack = IqStanza::selective_ack(1, vec!["0:a".into()]);
+        ack.id = "ack".into();
+
+        client.handle_iq(unknown);
+        client.handle_iq(ack);
+
+        assert_eq!(*handled.lock().unwrap(), ["unknown"]);
+        assert_eq!(client.last_rmq_id, Some(5));
+    }
 }
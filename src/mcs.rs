@@ -1,15 +1,55 @@
 include!(concat!(env!("OUT_DIR"), "/mcs_proto.rs"));
 
-use bytes::Buf;
-use log::warn;
+use bytes::{Buf, Bytes, BytesMut};
 use prost::Message as _;
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncReadExt as _};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use zeroize::Zeroizing;
+
+use crate::log::warn;
 
 pub type Tag = i8;
 
-impl LoginRequest {
-    pub const TAG: Tag = 2;
+/// Message type with an MCS tag.
+pub trait Tagged: prost::Message {
+    const TAG: Tag;
+}
+
+/// Associates a message type with its MCS tag.
+macro_rules! impl_tag {
+    ($($message:ident = $tag:literal),* $(,)?) => {$(
+        impl Tagged for $message {
+            const TAG: Tag = $tag;
+        }
+    )*};
+}
+
+impl_tag! {
+    HeartbeatPing = 0,
+    HeartbeatAck = 1,
+    LoginRequest = 2,
+    LoginResponse = 3,
+    Close = 4,
+    IqStanza = 7,
+    DataMessageStanza = 8,
+    StreamErrorStanza = 10,
+}
+
+/// Returns the name of a tag that is obsolete or has no message definition in this crate.
+///
+/// See `google_apis/gcm/base/mcs_util.h` in Chromium for the full list.
+fn unsupported_tag_name(tag: Tag) -> Option<&'static str> {
+    Some(match tag {
+        5 => "MessageStanza",
+        6 => "PresenceStanza",
+        9 => "BatchPresenceStanza",
+        11 => "HttpRequest",
+        12 => "HttpResponse",
+        13 => "BindAccountRequest",
+        14 => "BindAccountResponse",
+        15 => "TalkMetadata",
+        _ => return None,
+    })
 }
 
 #[allow(unused)]
@@ -22,20 +62,36 @@ pub enum Message {
     Close(Close),
     IqStanza(IqStanza),
     DataMessageStanza(DataMessageStanza),
+    StreamErrorStanza(StreamErrorStanza),
+    /// A message with an obsolete or unknown tag, skipped by the client.
+    Unknown {
+        tag: Tag,
+        data: Bytes,
+    },
 }
 
 // NOTE: some types should not be decoded as length delimited a.e. ping?
 impl Message {
-    pub fn decode<B: Buf>(buf: B, tag: Tag) -> Result<Self, DecodeError> {
+    pub fn decode<B: Buf>(mut buf: B, tag: Tag) -> Result<Self, DecodeError> {
         Ok(match tag {
-            0 => Self::HeartbeatPing(HeartbeatPing::decode(buf)?),
-            1 => Self::HeartbeatAck(HeartbeatAck::decode(buf)?),
-            2 => Self::LoginRequest(LoginRequest::decode(buf)?),
-            3 => Self::LoginResponse(LoginResponse::decode(buf)?),
-            4 => Self::Close(Close::decode(buf)?),
-            7 => Self::IqStanza(IqStanza::decode(buf)?),
-            8 => Self::DataMessageStanza(DataMessageStanza::decode(buf)?),
-            _ => return Err(DecodeError::UnknownTag { tag }),
+            HeartbeatPing::TAG => Self::HeartbeatPing(HeartbeatPing::decode(buf)?),
+            HeartbeatAck::TAG => Self::HeartbeatAck(HeartbeatAck::decode(buf)?),
+            LoginRequest::TAG => Self::LoginRequest(LoginRequest::decode(buf)?),
+            LoginResponse::TAG => Self::LoginResponse(LoginResponse::decode(buf)?),
+            Close::TAG => Self::Close(Close::decode(buf)?),
+            IqStanza::TAG => Self::IqStanza(IqStanza::decode(buf)?),
+            DataMessageStanza::TAG => Self::DataMessageStanza(DataMessageStanza::decode(buf)?),
+            StreamErrorStanza::TAG => Self::StreamErrorStanza(StreamErrorStanza::decode(buf)?),
+            _ => {
+                match unsupported_tag_name(tag) {
+                    Some(name) => warn!("skipping unsupported mcs message `{name}` (tag `{tag}`)"),
+                    None => warn!("skipping unknown mcs message with tag `{tag}`"),
+                }
+                Self::Unknown {
+                    tag,
+                    data: buf.copy_to_bytes(buf.remaining()),
+                }
+            }
         })
     }
 }
@@ -45,9 +101,131 @@ impl Message {
 #[error("failed to decode mcs message: {0}")]
 pub enum DecodeError {
     ProstDecode(#[from] prost::DecodeError),
-    #[error("unknown tag `{tag}`")]
-    UnknownTag {
-        tag: Tag,
+}
+
+/// Error reported by the server with a [`StreamErrorStanza`].
+#[derive(Error, Debug)]
+#[error("mcs stream error `{kind}`: {}", text.as_deref().unwrap_or("no details"))]
+pub struct StreamError {
+    /// Type of the error, as reported by the server.
+    pub kind: String,
+    /// Optional human-readable description of the error.
+    pub text: Option<String>,
+}
+
+impl From<StreamErrorStanza> for StreamError {
+    fn from(stanza: StreamErrorStanza) -> Self {
+        Self {
+            kind: stanza.r#type,
+            text: stanza.text,
+        }
+    }
+}
+
+/// IQ extension ids, see `google_apis/gcm/engine/mcs_client.cc` in Chromium.
+pub(crate) mod extension {
+    pub(crate) const SELECTIVE_ACK: i32 = 12;
+    pub(crate) const STREAM_ACK: i32 = 13;
+}
+
+/// Decoded [`Extension`] of an [`IqStanza`].
+#[derive(Debug)]
+pub enum IqExtension {
+    SelectiveAck(SelectiveAck),
+    StreamAck(StreamAck),
+    /// An extension the client does not interpret.
+    Other(Extension),
+}
+
+impl IqStanza {
+    /// Decodes the extension of the stanza, if any.
+    pub fn decode_extension(&self) -> Result<Option<IqExtension>, prost::DecodeError> {
+        let Some(extension) = &self.extension else {
+            return Ok(None);
+        };
+        let data = extension.data.as_slice();
+
+        Ok(Some(match extension.id {
+            extension::SELECTIVE_ACK => IqExtension::SelectiveAck(SelectiveAck::decode(data)?),
+            extension::STREAM_ACK => IqExtension::StreamAck(StreamAck::decode(data)?),
+            _ => IqExtension::Other(extension.clone()),
+        }))
+    }
+
+    /// Constructs a stanza acknowledging all messages received so far on the stream.
+    pub(crate) fn stream_ack(last_stream_id_received: i32) -> Self {
+        Self::with_extension(
+            last_stream_id_received,
+            Extension {
+                id: extension::STREAM_ACK,
+                data: StreamAck {}.encode_to_vec(),
+            },
+        )
+    }
+
+    /// Constructs a stanza acknowledging the messages with the given persistent ids.
+    pub(crate) fn selective_ack(last_stream_id_received: i32, id: Vec<String>) -> Self {
+        Self::with_extension(
+            last_stream_id_received,
+            Extension {
+                id: extension::SELECTIVE_ACK,
+                data: SelectiveAck { id }.encode_to_vec(),
+            },
+        )
+    }
+
+    fn with_extension(last_stream_id_received: i32, extension: Extension) -> Self {
+        Self {
+            r#type: iq_stanza::IqType::Set.into(),
+            id: String::new(),
+            extension: extension.into(),
+            last_stream_id_received: last_stream_id_received.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Number of unacknowledged messages after which the client sends a [`StreamAck`].
+pub(crate) const UNACKED_MESSAGES_BEFORE_STREAM_ACK: usize = 10;
+
+/// Per-connection stream id bookkeeping.
+///
+/// Stream ids are no longer sent by the server, so each side counts the messages it has sent and
+/// received since login.
+#[derive(Debug, Default)]
+pub(crate) struct StreamIds {
+    /// Id of the last message sent to the server.
+    pub out: i32,
+    /// Id of the last message received from the server.
+    pub last_received: i32,
+    /// Number of received messages not yet acknowledged to the server.
+    pub unacked: usize,
+}
+
+impl StreamIds {
+    /// Constructs the type for a connection whose login request has been sent.
+    pub(crate) fn new() -> Self {
+        Self {
+            out: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Records a message received from the server.
+    pub(crate) fn on_received(&mut self) {
+        self.last_received += 1;
+        self.unacked += 1;
+    }
+
+    /// Records a message sent to the server, which acknowledges everything received so far.
+    pub(crate) fn on_sent(&mut self) {
+        self.out += 1;
+        self.unacked = 0;
+    }
+
+    /// Returns true if the client should acknowledge received messages with a [`StreamAck`].
+    pub(crate) fn needs_stream_ack(&self) -> bool {
+        self.unacked >= UNACKED_MESSAGES_BEFORE_STREAM_ACK
     }
 }
 
@@ -88,42 +266,123 @@ impl MissingDataError {
     }
 }
 
-pub(crate) trait AsyncReadExt {
-    ///// Reads a VLQ (variable length quantity) value as an u32 from the underlying reader.
-    async fn read_vlq_u32(&mut self) -> Result<u32, ReadError>
-    where
-        Self: AsyncRead + Unpin,
-    {
-        let mut shift = 0;
-        let mut value = 0;
-
-        for _ in 0..4 {
-            let next = self.read_u8().await?;
-            value |= ((next & 0b01111111) as u32) << shift;
-            if next & 0b10000000 == 0 {
-                return Ok(value);
+/// Default upper bound for the size of a single MCS frame.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Buffered reader that decodes MCS frames from the underlying reader.
+///
+/// The reader keeps partially received frames in an internal buffer, which makes
+/// [`MessageReader::read_message()`] cancel safe, and reuses the buffer allocation between frames.
+pub(crate) struct MessageReader<R> {
+    inner: R,
+    buffer: BytesMut,
+    max_frame_size: usize,
+}
+
+impl<R> MessageReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Constructs the reader, rejecting frames larger than `max_frame_size`.
+    pub(crate) fn new(inner: R, max_frame_size: usize) -> Self {
+        Self {
+            inner,
+            buffer: BytesMut::with_capacity(8 * 1024),
+            max_frame_size,
+        }
+    }
+
+    /// Reads an MCS message from the underlying reader.
+    ///
+    /// This method is cancel safe, no data is lost if it is used in `tokio::select!`.
+    pub(crate) async fn read_message(&mut self) -> Result<Message, ReadError> {
+        loop {
+            if let Some(header) = self.parse_header()? {
+                let frame_size = header.len + header.size;
+                if self.buffer.len() >= frame_size {
+                    self.buffer.advance(header.len);
+                    let data = self.buffer.split_to(header.size);
+                    return Ok(Message::decode(data, header.tag)?);
+                }
+                self.buffer.reserve(frame_size - self.buffer.len());
             }
-            shift += 7;
+            if self.inner.read_buf(&mut self.buffer).await? == 0 {
+                return Err(ReadError::UnexpectedEof);
+            }
+        }
+    }
+
+    /// Parses the tag and size of the next frame, if enough data is buffered.
+    fn parse_header(&self) -> Result<Option<FrameHeader>, ReadError> {
+        let Some((&tag, rest)) = self.buffer.split_first() else {
+            return Ok(None);
+        };
+        let Some((size, vlq_len)) = decode_vlq_u32(rest)? else {
+            return Ok(None);
+        };
+        let size = size as usize;
+        if size > self.max_frame_size {
+            return Err(ReadError::FrameTooLarge {
+                size,
+                max: self.max_frame_size,
+            });
         }
 
-        Err(ReadError::VlqTooLarge)
+        Ok(Some(FrameHeader {
+            tag: tag as Tag,
+            size,
+            len: 1 + vlq_len,
+        }))
     }
-    // Reads an MCS message from the underlying reader.
-    async fn read_message(&mut self) -> Result<Message, ReadError>
+}
+
+/// Tag and size prefix of an MCS frame.
+struct FrameHeader {
+    tag: Tag,
+    /// Size of the frame body.
+    size: usize,
+    /// Length of the header itself.
+    len: usize,
+}
+
+/// Decodes a VLQ (variable length quantity) value as an u32 from the start of `buf`, returning the
+/// value and the number of bytes it occupies, or `None` if `buf` ends before the value does.
+fn decode_vlq_u32(buf: &[u8]) -> Result<Option<(u32, usize)>, ReadError> {
+    let mut shift = 0;
+    let mut value = 0;
+
+    for (index, &next) in buf.iter().take(4).enumerate() {
+        value |= ((next & 0b01111111) as u32) << shift;
+        if next & 0b10000000 == 0 {
+            return Ok(Some((value, index + 1)));
+        }
+        shift += 7;
+    }
+
+    match buf.len() {
+        0..=3 => Ok(None),
+        _ => Err(ReadError::VlqTooLarge),
+    }
+}
+
+pub(crate) trait AsyncWriteExt {
+    /// Writes a tagged, length delimited MCS message to the underlying writer.
+    async fn write_message<M: Tagged>(&mut self, message: &M) -> Result<(), tokio::io::Error>
     where
-        Self: AsyncRead + Unpin,
+        Self: AsyncWrite + Unpin,
     {
-        let tag = self.read_i8().await?;
-        let size = self.read_vlq_u32().await?;
-        let mut data = vec![0u8; size as usize];
+        // Zeroized as messages may contain secrets, e.g. the auth token of the login request.
+        let mut buf = Zeroizing::new(Vec::with_capacity(1 + message.encoded_len() + 5));
+        buf.push(M::TAG as u8);
+        message
+            .encode_length_delimited(&mut *buf)
+            .expect("vec has sufficient capacity");
 
-        self.read_exact(data.as_mut_slice()).await?;
-
-        Ok(Message::decode(data.as_slice(), tag)?)
+        self.write_all(&buf).await
     }
 }
 
-impl<R: AsyncRead + ?Sized> AsyncReadExt for R {}
+impl<W: AsyncWrite + ?Sized> AsyncWriteExt for W {}
 
 #[derive(Debug, Error)]
 #[error("failed to read value: {0}")]
@@ -132,4 +391,191 @@ pub enum ReadError {
     ProtoDecode(#[from] DecodeError),
     #[error("vlq value too large")]
     VlqTooLarge,
+    #[error("frame of {size} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+    #[error("connection closed by peer")]
+    UnexpectedEof,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    /// Reader returning one chunk per read.
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Chunks {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if let Some(chunk) = self.0.pop_front() {
+                buf.put_slice(&chunk);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn reader(chunks: &[&[u8]], max_frame_size: usize) -> MessageReader<Chunks> {
+        let chunks = chunks.iter().map(|chunk| chunk.to_vec()).collect();
+        MessageReader::new(Chunks(chunks), max_frame_size)
+    }
+
+    async fn frame<M: Tagged>(message: &M) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_message(message).await.unwrap();
+        buf
+    }
+
+    fn data_message(persistent_id: &str, size: usize) -> DataMessageStanza {
+        DataMessageStanza {
+            persistent_id: Some(persistent_id.into()),
+            raw_data: Some(vec![7; size]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decodes_vlq() {
+        assert_eq!(decode_vlq_u32(&[0x05]).unwrap(), Some((5, 1)));
+        assert_eq!(decode_vlq_u32(&[0xac, 0x02, 0xff]).unwrap(), Some((300, 2)));
+        assert_eq!(decode_vlq_u32(&[]).unwrap(), None);
+        assert_eq!(decode_vlq_u32(&[0x80, 0x80, 0x80]).unwrap(), None);
+        assert!(matches!(
+            decode_vlq_u32(&[0x80, 0x80, 0x80, 0x80]),
+            Err(ReadError::VlqTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_frame_with_split_size() {
+        let frame = frame(&data_message("split-vlq", 200)).await;
+        // The size takes two bytes, split between the first two reads.
+        let mut reader = reader(&[&frame[..2], &frame[2..]], usize::MAX);
+
+        let Message::DataMessageStanza(message) = reader.read_message().await.unwrap() else {
+            panic!("expected a data message");
+        };
+        assert_eq!(message.persistent_id(), "split-vlq");
+    }
+
+    #[tokio::test]
+    async fn reads_frame_with_split_body() {
+        let first = frame(&data_message("first", 100)).await;
+        let second = frame(&data_message("second", 10)).await;
+        let mut reader = reader(
+            &[&first[..50], &first[50..], &second[..5], &second[5..]],
+            usize::MAX,
+        );
+
+        for expected in ["first", "second"] {
+            let Message::DataMessageStanza(message) = reader.read_message().await.unwrap() else {
+                panic!("expected a data message");
+            };
+            assert_eq!(message.persistent_id(), expected);
+        }
+        assert!(matches!(
+            reader.read_message().await,
+            Err(ReadError::UnexpectedEof)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_frame() {
+        let frame = frame(&data_message("large", 100)).await;
+        let mut reader = reader(&[&frame[..3]], 64);
+
+        assert!(matches!(
+            reader.read_message().await,
+            Err(ReadError::FrameTooLarge { max: 64, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn skips_unknown_tag() {
+        let mut bytes = vec![99, 3, 1, 2, 3];
+        bytes.extend(frame(&HeartbeatPing::default()).await);
+        let mut reader = reader(&[&bytes], usize::MAX);
+
+        let message = reader.read_message().await.unwrap();
+        assert!(matches!(message, Message::Unknown { tag: 99, ref data } if data[..] == [1, 2, 3]));
+        assert!(matches!(
+            reader.read_message().await.unwrap(),
+            Message::HeartbeatPing(_)
+        ));
+    }
+
+    #[test]
+    fn round_trips_ack_extensions() {
+        let ids = vec!["0:a".to_string(), "0:b".to_string()];
+        let stanza = IqStanza::selective_ack(7, ids.clone());
+        assert_eq!(stanza.last_stream_id_received, Some(7));
+        assert_eq!(stanza.r#type(), iq_stanza::IqType::Set);
+        let Some(IqExtension::SelectiveAck(ack)) = stanza.decode_extension().unwrap() else {
+            panic!("expected a selective ack");
+        };
+        assert_eq!(ack.id, ids);
+
+        let stanza = IqStanza::stream_ack(3);
+        assert!(matches!(
+            stanza.decode_extension().unwrap(),
+            Some(IqExtension::StreamAck(_))
+        ));
+    }
+
+    #[test]
+    fn decodes_unknown_and_missing_extension() {
+        let extension = Extension {
+            id: 99,
+            data: vec![1, 2],
+        };
+        let stanza = IqStanza {
+            extension: Some(extension.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            stanza.decode_extension().unwrap(),
+            Some(IqExtension::Other(other)) if other == extension
+        ));
+        assert!(IqStanza::default().decode_extension().unwrap().is_none());
+
+        let corrupt = IqStanza {
+            extension: Some(Extension {
+                id: extension::SELECTIVE_ACK,
+                data: vec![0xff],
+            }),
+            ..Default::default()
+        };
+        assert!(corrupt.decode_extension().is_err());
+    }
+
+    #[test]
+    fn requests_stream_ack_after_unacked_messages() {
+        let mut stream_ids = StreamIds::new();
+        for _ in 1..UNACKED_MESSAGES_BEFORE_STREAM_ACK {
+            stream_ids.on_received();
+        }
+        assert!(!stream_ids.needs_stream_ack());
+        stream_ids.on_received();
+        assert!(stream_ids.needs_stream_ack());
+        assert_eq!(
+            stream_ids.last_received,
+            UNACKED_MESSAGES_BEFORE_STREAM_ACK as i32
+        );
+
+        // Any message sent to the server acknowledges the received ones.
+        stream_ids.on_sent();
+        assert!(!stream_ids.needs_stream_ack());
+        assert_eq!(stream_ids.out, 2);
+    }
 }
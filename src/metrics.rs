@@ -0,0 +1,350 @@
+//! Metrics of [`Client`](crate::Client)s, rendered in the Prometheus text format.
+use std::fmt::Write as _;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::log;
+
+/// Upper bounds of the heartbeat round-trip time histogram buckets, in seconds.
+const RTT_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// Largest HTTP request head read by [`Metrics::serve()`].
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Counters and histograms shared by any number of clients.
+///
+/// Pass a clone to [`Client::with_metrics()`](crate::Client::with_metrics) and expose
+/// [`Metrics::render()`] to Prometheus, e.g. with [`Metrics::serve()`].
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    connects: AtomicU64,
+    reconnect_attempts: AtomicU64,
+    login_failures: AtomicU64,
+    messages_received: AtomicU64,
+    messages_duplicated: AtomicU64,
+    messages_decrypt_failed: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    heartbeat_rtt: Histogram,
+    /// Unix time of the latest message in milliseconds, zero before the first one.
+    last_message_ms: AtomicU64,
+}
+
+/// Histogram with the [`RTT_BUCKETS`].
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; RTT_BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(RTT_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let inner = &self.0;
+        let mut out = String::new();
+        let counters = [
+            (
+                "connects",
+                "Successful connections to FCM.",
+                &inner.connects,
+            ),
+            (
+                "reconnect_attempts",
+                "Connection attempts retried after a failure.",
+                &inner.reconnect_attempts,
+            ),
+            (
+                "login_failures",
+                "Login responses with an error.",
+                &inner.login_failures,
+            ),
+            (
+                "messages_received",
+                "Data messages received, including duplicates.",
+                &inner.messages_received,
+            ),
+            (
+                "messages_duplicated",
+                "Data messages dropped as already delivered.",
+                &inner.messages_duplicated,
+            ),
+            (
+                "messages_decrypt_failed",
+                "Data messages that failed to decrypt.",
+                &inner.messages_decrypt_failed,
+            ),
+            (
+                "bytes_received",
+                "Bytes read from MCS connections.",
+                &inner.bytes_received,
+            ),
+            (
+                "bytes_sent",
+                "Bytes written to MCS connections.",
+                &inner.bytes_sent,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let name = format!("fcm_receiver_{name}_total");
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let rtt = &inner.heartbeat_rtt;
+        let name = "fcm_receiver_heartbeat_rtt_seconds";
+        let _ = writeln!(out, "# HELP {name} Round-trip time of heartbeat pings.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bucket, bound) in rtt.buckets.iter().zip(RTT_BUCKETS) {
+            let count = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let count = rtt.count.load(Ordering::Relaxed);
+        let sum = rtt.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+
+        let last_message_ms = inner.last_message_ms.load(Ordering::Relaxed);
+        if last_message_ms > 0 {
+            let name = "fcm_receiver_seconds_since_last_message";
+            let elapsed = unix_millis().saturating_sub(last_message_ms) as f64 / 1e3;
+            let _ = writeln!(out, "# HELP {name} Time since the latest data message.");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {elapsed}");
+        }
+        out
+    }
+
+    /// Answers every HTTP request on `listener` with the rendered metrics.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                if let Err(error) = metrics.respond(stream).await {
+                    log::debug!("failed to serve metrics: {error}");
+                }
+            });
+        }
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        // The request is not inspected, only read to the end of its head.
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+            if stream.read_buf(&mut request).await? == 0 {
+                break;
+            }
+        }
+        let body = self.render();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             content-type: text/plain; version=0.0.4\r\n\
+             content-length: {}\r\n\
+             connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    pub(crate) fn on_connected(&self) {
+        self.0.connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_reconnect_attempt(&self) {
+        self.0.reconnect_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_login_failed(&self) {
+        self.0.login_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_message(&self) {
+        self.0.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .last_message_ms
+            .store(unix_millis(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_duplicate(&self) {
+        self.0.messages_duplicated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_decrypt_failed(&self) {
+        self.0
+            .messages_decrypt_failed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_heartbeat_ack(&self, round_trip: Duration) {
+        self.0.heartbeat_rtt.observe(round_trip);
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Stream counting the bytes read and written in [`Metrics`].
+#[derive(Debug)]
+pub(crate) struct Counted<S> {
+    inner: S,
+    metrics: Option<Metrics>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S, metrics: Option<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(metrics)) = (&poll, &self.metrics) {
+            let read = (buf.filled().len() - filled) as u64;
+            metrics.0.bytes_received.fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(metrics)) = (&poll, &self.metrics) {
+            metrics
+                .0
+                .bytes_sent
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_histogram() {
+        let metrics = Metrics::new();
+        metrics.on_connected();
+        metrics.on_message();
+        metrics.on_message();
+        metrics.on_heartbeat_ack(Duration::from_millis(200));
+        metrics.on_heartbeat_ack(Duration::from_secs(3));
+
+        let rendered = metrics.render();
+        let lines: Vec<_> = rendered.lines().collect();
+
+        for line in [
+            "# TYPE fcm_receiver_connects_total counter",
+            "fcm_receiver_connects_total 1",
+            "fcm_receiver_reconnect_attempts_total 0",
+            "fcm_receiver_messages_received_total 2",
+            "# TYPE fcm_receiver_heartbeat_rtt_seconds histogram",
+            "# TYPE fcm_receiver_seconds_since_last_message gauge",
+        ] {
+            assert!(lines.contains(&line), "missing `{line}` in:\n{rendered}");
+        }
+        let histogram: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("fcm_receiver_heartbeat_rtt_seconds"))
+            .copied()
+            .collect();
+        assert_eq!(
+            histogram,
+            [
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="0.05"} 0"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="0.1"} 0"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="0.25"} 1"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="0.5"} 1"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="1"} 1"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="2.5"} 1"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="5"} 2"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="10"} 2"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="30"} 2"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="60"} 2"#,
+                r#"fcm_receiver_heartbeat_rtt_seconds_bucket{le="+Inf"} 2"#,
+                "fcm_receiver_heartbeat_rtt_seconds_sum 3.2",
+                "fcm_receiver_heartbeat_rtt_seconds_count 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn omits_last_message_before_the_first() {
+        assert!(!Metrics::new()
+            .render()
+            .contains("fcm_receiver_seconds_since_last_message"));
+    }
+
+    #[tokio::test]
+    async fn counts_bytes() {
+        let metrics = Metrics::new();
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut counted = Counted::new(client, Some(metrics.clone()));
+
+        counted.write_all(b"login").await.unwrap();
+        server.write_all(b"response").await.unwrap();
+        let mut response = [0; 8];
+        counted.read_exact(&mut response).await.unwrap();
+
+        assert_eq!(metrics.0.bytes_sent.load(Ordering::Relaxed), 5);
+        assert_eq!(metrics.0.bytes_received.load(Ordering::Relaxed), 8);
+        let rendered = metrics.render();
+        assert!(rendered.contains("fcm_receiver_bytes_sent_total 5\n"));
+        assert!(rendered.contains("fcm_receiver_bytes_received_total 8\n"));
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use crate::mcs::DataMessageStanza;
+use crate::vapid;
+use crate::webpush::{app_data, CATEGORY};
+use crate::AckHandle;
+
+/// Message received from FCM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// Server assigned ID, used to acknowledge the message.
+    pub persistent_id: String,
+    /// Sender of the message.
+    pub from: String,
+    /// Category the message was sent to, usually the app ID.
+    pub category: String,
+    /// App ID of the registration the message was routed to.
+    pub app_id: String,
+    /// Time the message was sent, as reported by the server.
+    pub sent: Option<i64>,
+    /// Time to live in seconds, if set by the sender.
+    pub ttl: Option<i32>,
+    /// Key/value data of the message, without the web push encryption headers.
+    pub data: HashMap<String, String>,
+    /// Decrypted payload of an encrypted message, the raw payload otherwise.
+    pub payload: Vec<u8>,
+    /// Whether the payload was end-to-end encrypted.
+    pub encrypted: bool,
+    /// Base64 encoded VAPID public key of the sender, if any.
+    pub vapid_key: Option<String>,
+    /// Whether the sender is trusted, `None` if no
+    /// [`TrustedSenders`](crate::vapid::TrustedSenders) are configured.
+    pub trusted: Option<bool>,
+    /// Handle acknowledging the notification, if
+    /// [`Client::with_manual_ack()`](crate::Client::with_manual_ack) is enabled.
+    pub ack: Option<AckHandle>,
+    /// Span the message was received in, for continuing its trace.
+    #[cfg(feature = "tracing")]
+    pub span: NotificationSpan,
+}
+
+/// Span a [`Notification`] was received in.
+///
+/// All spans compare equal, as the span is not part of the message.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone)]
+pub struct NotificationSpan(pub tracing::Span);
+
+#[cfg(feature = "tracing")]
+impl PartialEq for NotificationSpan {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Eq for NotificationSpan {}
+
+#[cfg(feature = "tracing")]
+impl std::ops::Deref for NotificationSpan {
+    type Target = tracing::Span;
+
+    fn deref(&self) -> &tracing::Span {
+        &self.0
+    }
+}
+
+impl Notification {
+    /// Builds a notification from a stanza, with an already decrypted `payload` if encrypted.
+    pub(crate) fn new(message: DataMessageStanza, payload: Option<Vec<u8>>) -> Self {
+        let encrypted = payload.is_some();
+        let vapid_key = vapid::vapid_key(&message).map(Into::into);
+        let app_id = message.app_id().to_string();
+        let data = message
+            .app_data
+            .into_iter()
+            .filter(|data| !encrypted || !is_encryption_header(&data.key))
+            .map(|data| (data.key, data.value))
+            .collect();
+
+        Self {
+            persistent_id: message.persistent_id.unwrap_or_default(),
+            from: message.from,
+            category: message.category,
+            app_id,
+            sent: message.sent,
+            ttl: message.ttl,
+            data,
+            payload: payload.or(message.raw_data).unwrap_or_default(),
+            encrypted,
+            vapid_key,
+            trusted: None,
+            ack: None,
+            #[cfg(feature = "tracing")]
+            span: NotificationSpan(tracing::Span::current()),
+        }
+    }
+}
+
+impl DataMessageStanza {
+    /// Returns the app ID the message was sent to.
+    ///
+    /// Messages for web push registrations share the Chromium category and carry the app ID in
+    /// the `subtype` app-data.
+    pub(crate) fn app_id(&self) -> &str {
+        match self.category == CATEGORY {
+            true => self
+                .app_data(app_data::SUBTYPE)
+                .map_or(&self.category, |data| &data.value),
+            false => &self.category,
+        }
+    }
+
+    /// Returns true if the message carries a web push encrypted payload.
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.app_data
+            .iter()
+            .any(|data| is_encryption_header(&data.key))
+    }
+}
+
+fn is_encryption_header(key: &str) -> bool {
+    matches!(
+        key,
+        app_data::CRYPTO_KEY | app_data::ENCRYPTION | app_data::CONTENT_ENCODING
+    )
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+use std::sync::Arc;
+
+use futures_util::{Stream, StreamExt as _};
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::log;
+use crate::{Client, Notification};
+
+/// Filter deciding which notifications a [`Subscription`] receives.
+type Filter = Arc<dyn Fn(&Notification) -> bool + Send + Sync>;
+
+/// Handle of a connection task distributing notifications to any number of [`Subscription`]s.
+///
+/// Each subscription buffers up to `capacity` notifications. A subscription that falls further
+/// behind misses the oldest ones and is told so with [`RecvError::Lagged`], so a slow consumer
+/// never stalls the connection. Errors of the connection are logged. Dropping the receiver
+/// stops the connection task.
+#[derive(Debug)]
+pub struct Receiver {
+    sender: broadcast::Sender<Arc<Notification>>,
+    task: JoinHandle<()>,
+}
+
+impl Receiver {
+    /// Spawns a task receiving notifications with `client`.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn spawn(mut client: Client, capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        let task = tokio::spawn({
+            let sender = sender.clone();
+            async move {
+                let mut notifications = std::pin::pin!(client.notifications());
+                while let Some(notification) = notifications.next().await {
+                    match notification {
+                        // Fails only without subscriptions.
+                        Ok(notification) => drop(sender.send(Arc::new(notification))),
+                        Err(error) => log::error!("{error}"),
+                    }
+                }
+            }
+        });
+
+        Self { sender, task }
+    }
+
+    /// Returns a subscription receiving notifications from now on.
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            receiver: self.sender.subscribe(),
+            filter: None,
+        }
+    }
+
+    /// Returns a subscription receiving notifications from now on for which `filter` returns
+    /// true.
+    pub fn subscribe_with(
+        &self,
+        filter: impl Fn(&Notification) -> bool + Send + Sync + 'static,
+    ) -> Subscription {
+        Subscription {
+            receiver: self.sender.subscribe(),
+            filter: Some(Arc::new(filter)),
+        }
+    }
+
+    /// Returns true once the connection task has stopped.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Subscription to the notifications of a [`Receiver`].
+///
+/// Cloning a subscription creates a new one with the same filter, receiving notifications from
+/// the time of the clone.
+pub struct Subscription {
+    receiver: broadcast::Receiver<Arc<Notification>>,
+    filter: Option<Filter>,
+}
+
+impl Subscription {
+    /// Waits for the next notification.
+    pub async fn recv(&mut self) -> Result<Arc<Notification>, RecvError> {
+        loop {
+            let notification = self.receiver.recv().await.map_err(|error| match error {
+                broadcast::error::RecvError::Lagged(count) => RecvError::Lagged(count),
+                broadcast::error::RecvError::Closed => RecvError::Closed,
+            })?;
+            if self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter(&notification))
+            {
+                return Ok(notification);
+            }
+        }
+    }
+
+    /// Converts the subscription into a stream, ending when the [`Receiver`] is dropped.
+    pub fn into_stream(mut self) -> impl Stream<Item = Result<Arc<Notification>, RecvError>> {
+        async_stream::stream! {
+            loop {
+                match self.recv().await {
+                    Err(RecvError::Closed) => break,
+                    result => yield result,
+                }
+            }
+        }
+    }
+}
+
+impl Clone for Subscription {
+    fn clone(&self) -> Self {
+        Self {
+            receiver: self.receiver.resubscribe(),
+            filter: self.filter.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("filtered", &self.filter.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Error returned by [`Subscription::recv()`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RecvError {
+    /// The subscription fell behind and missed this many notifications, including ones its
+    /// filter would have skipped.
+    #[error("subscription lagged behind by {0} notifications")]
+    Lagged(u64),
+    /// The [`Receiver`] was dropped or its connection task stopped.
+    #[error("receiver closed")]
+    Closed,
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+use std::time::Duration;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcPoint, PointConversionForm};
+use openssl::nid::Nid;
+use thiserror::Error;
+
+use crate::{ClientError, Credentials};
+
+/// Length of a raw P-256 private key.
+const PRIVATE_KEY_LEN: usize = 32;
+/// Length of an uncompressed P-256 public key.
+const PUBLIC_KEY_LEN: usize = 65;
+/// Length of a web-push auth secret.
+const AUTH_SECRET_LEN: usize = 16;
+
+/// Problem found by [`Credentials::validate()`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ValidationIssue {
+    #[error("`{field}` is empty")]
+    Empty { field: &'static str },
+    #[error("`{field}` is not a non-zero unsigned integer")]
+    NotNumeric { field: &'static str },
+    #[error("`{field}` contains unexpected characters")]
+    Malformed { field: &'static str },
+    #[error("`{field}` is not valid unpadded url-safe base64")]
+    InvalidBase64 { field: &'static str },
+    #[error("`{field}` is {actual} bytes long (expected {expected})")]
+    InvalidLength {
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("`keys.privateKey` is not a valid P-256 private key")]
+    InvalidPrivateKey,
+    #[error("`keys.publicKey` does not match `keys.privateKey`")]
+    KeyMismatch,
+}
+
+/// Error returned by [`Credentials::validate()`].
+#[derive(Debug, Error)]
+pub struct ValidationError {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid credentials: ")?;
+        for (index, issue) in self.issues.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{issue}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Credentials {
+    /// Checks that all fields are well-formed and that the key pair is consistent.
+    ///
+    /// Returns every issue found rather than stopping at the first one.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut issues = Vec::new();
+
+        check_numeric(&mut issues, "gcm.androidId", &self.gcm.android_id);
+        check_numeric(
+            &mut issues,
+            "gcm.securityToken",
+            self.gcm.security_token.expose(),
+        );
+        check_token(&mut issues, "gcm.token", self.gcm.token.expose());
+        check_token(&mut issues, "fcm.token", self.fcm.token.expose());
+        if self.gcm.app_id.is_empty() {
+            issues.push(ValidationIssue::Empty { field: "gcm.appId" });
+        }
+
+        let private_key = decode(
+            &mut issues,
+            "keys.privateKey",
+            self.keys.private_key.expose(),
+            PRIVATE_KEY_LEN,
+        );
+        let public_key = decode(
+            &mut issues,
+            "keys.publicKey",
+            &self.keys.public_key,
+            PUBLIC_KEY_LEN,
+        );
+        decode(
+            &mut issues,
+            "keys.authSecret",
+            self.keys.auth_secret.expose(),
+            AUTH_SECRET_LEN,
+        );
+        if let (Some(private_key), Some(public_key)) = (private_key, public_key) {
+            match derive_public_key(&private_key) {
+                Some(derived) if derived == public_key => (),
+                Some(_) => issues.push(ValidationIssue::KeyMismatch),
+                None => issues.push(ValidationIssue::InvalidPrivateKey),
+            }
+        }
+
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(ValidationError { issues }),
+        }
+    }
+}
+
+fn check_numeric(issues: &mut Vec<ValidationIssue>, field: &'static str, value: &str) {
+    if !matches!(value.parse::<u64>(), Ok(value) if value != 0) {
+        issues.push(ValidationIssue::NotNumeric { field });
+    }
+}
+
+/// Checks that a registration token is non-empty and only contains url-safe characters.
+fn check_token(issues: &mut Vec<ValidationIssue>, field: &'static str, value: &str) {
+    if value.is_empty() {
+        issues.push(ValidationIssue::Empty { field });
+    } else if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+    {
+        issues.push(ValidationIssue::Malformed { field });
+    }
+}
+
+fn decode(
+    issues: &mut Vec<ValidationIssue>,
+    field: &'static str,
+    value: &str,
+    expected: usize,
+) -> Option<Vec<u8>> {
+    let Ok(bytes) = BASE64_URL_SAFE_NO_PAD.decode(value) else {
+        issues.push(ValidationIssue::InvalidBase64 { field });
+        return None;
+    };
+    if bytes.len() != expected {
+        issues.push(ValidationIssue::InvalidLength {
+            field,
+            expected,
+            actual: bytes.len(),
+        });
+        return None;
+    }
+    Some(bytes)
+}
+
+/// Derives the uncompressed public key of a raw P-256 private key.
+fn derive_public_key(private_key: &[u8]) -> Option<Vec<u8>> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).ok()?;
+    let mut context = BigNumContext::new().ok()?;
+    let scalar = BigNum::from_slice(private_key).ok()?;
+    let mut order = BigNum::new().ok()?;
+    group.order(&mut order, &mut context).ok()?;
+    if scalar.num_bits() == 0 || scalar >= order {
+        return None;
+    }
+    let mut point = EcPoint::new(&group).ok()?;
+    point.mul_generator2(&group, &scalar, &mut context).ok()?;
+
+    point
+        .to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut context)
+        .ok()
+}
+
+/// Report returned by [`Client::verify()`](crate::Client::verify).
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// Result of the GCM check-in and the time it took.
+    pub check_in: Result<Duration, ClientError>,
+    /// Result of the MCS login, `None` if the check-in failed.
+    pub login: Option<Result<LoginReport, ClientError>>,
+}
+
+impl VerifyReport {
+    /// Returns true if both the check-in and the login succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.check_in.is_ok() && matches!(self.login, Some(Ok(_)))
+    }
+}
+
+/// Details of a successful MCS login.
+#[derive(Debug)]
+pub struct LoginReport {
+    /// Time from opening the connection to receiving the login response.
+    pub duration: Duration,
+    /// Server time in milliseconds since the unix epoch, if reported.
+    pub server_timestamp: Option<i64>,
+    /// Heartbeat interval requested by the server, if any.
+    pub heartbeat_interval: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Keys;
+
+    fn issues(credentials: &Credentials) -> Vec<ValidationIssue> {
+        credentials.validate().unwrap_err().issues
+    }
+
+    #[test]
+    fn accepts_valid_credentials() {
+        Credentials::for_test().validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_key_pair() {
+        let mut credentials = Credentials::for_test();
+        credentials.keys.public_key = Keys::new().unwrap().public_key;
+
+        assert_eq!(issues(&credentials), [ValidationIssue::KeyMismatch]);
+    }
+
+    #[test]
+    fn rejects_invalid_key_encoding() {
+        let mut credentials = Credentials::for_test();
+        credentials.keys.public_key = "not base64!".into();
+        credentials.keys.auth_secret = BASE64_URL_SAFE_NO_PAD.encode([0; 8]).into();
+
+        assert_eq!(
+            issues(&credentials),
+            [
+                ValidationIssue::InvalidBase64 {
+                    field: "keys.publicKey"
+                },
+                ValidationIssue::InvalidLength {
+                    field: "keys.authSecret",
+                    expected: AUTH_SECRET_LEN,
+                    actual: 8,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_non_numeric_android_id() {
+        let mut credentials = Credentials::for_test();
+        credentials.gcm.android_id = "android-1234".into();
+
+        assert_eq!(
+            issues(&credentials),
+            [ValidationIssue::NotNumeric {
+                field: "gcm.androidId"
+            }]
+        );
+    }
+
+    #[test]
+    fn collects_all_issues() {
+        let mut credentials = Credentials::for_test();
+        credentials.gcm.security_token = String::from("0").into();
+        credentials.gcm.app_id = String::new();
+        credentials.fcm.token = String::from("fcm token").into();
+        credentials.keys.private_key = BASE64_URL_SAFE_NO_PAD.encode([0; 32]).into();
+
+        let error = credentials.validate().unwrap_err();
+
+        assert_eq!(
+            error.issues,
+            [
+                ValidationIssue::NotNumeric {
+                    field: "gcm.securityToken"
+                },
+                ValidationIssue::Malformed { field: "fcm.token" },
+                ValidationIssue::Empty { field: "gcm.appId" },
+                ValidationIssue::InvalidPrivateKey,
+            ]
+        );
+        assert_eq!(
+            error.to_string(),
+            "invalid credentials: `gcm.securityToken` is not a non-zero unsigned integer, \
+             `fcm.token` contains unexpected characters, `gcm.appId` is empty, \
+             `keys.privateKey` is not a valid P-256 private key"
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Verification of the VAPID information attached to incoming web push messages.
+//!
+//! The sender's VAPID public key is read from the `p256ecdsa` parameter of `crypto-key` or the
+//! `k` parameter of `authorization`. The key is only trusted together with the `authorization`
+//! JWT, whose ES256 signature, audience and expiry are checked against it. The expiry is checked
+//! against the time the message was sent, so messages delayed by FCM remain trusted.
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use openssl::sha::sha256;
+
+use crate::mcs::DataMessageStanza;
+use crate::webpush::{app_data, decode_base64, header_param};
+
+/// Length of an ES256 signature.
+const SIGNATURE_LEN: usize = 64;
+/// Origin of the FCM push endpoints, the audience of VAPID JWTs sent through FCM.
+const AUDIENCE: &str = "https://fcm.googleapis.com";
+/// Longest validity of a VAPID JWT, see RFC 8292.
+const MAX_EXPIRY_SECS: u64 = 24 * 60 * 60;
+/// Allowed difference between the clocks of the sender and the FCM server.
+const CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// What the [`Client`](crate::Client) does with messages from senders that are not trusted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UntrustedAction {
+    /// Drops the message.
+    #[default]
+    Reject,
+    /// Delivers the message with [`Notification::trusted`](crate::Notification::trusted) set
+    /// to `false`.
+    Flag,
+}
+
+/// Allow-list of senders whose messages are trusted.
+///
+/// A message is trusted if it was sent by one of the sender IDs, or carries a valid VAPID JWT
+/// signed with one of the VAPID keys.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedSenders {
+    vapid_keys: HashSet<Vec<u8>>,
+    sender_ids: HashSet<String>,
+    action: UntrustedAction,
+}
+
+impl TrustedSenders {
+    /// Creates an empty allow-list, rejecting every message.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts messages signed with the base64 encoded VAPID public key.
+    pub fn with_vapid_key(mut self, public_key: &str) -> Result<Self, base64::DecodeError> {
+        self.vapid_keys.insert(decode_base64(public_key)?);
+        Ok(self)
+    }
+
+    /// Trusts messages sent by the sender ID.
+    pub fn with_sender_id(mut self, sender_id: impl Into<String>) -> Self {
+        self.sender_ids.insert(sender_id.into());
+        self
+    }
+
+    /// Sets what happens to messages from untrusted senders.
+    pub fn with_action(mut self, action: UntrustedAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Returns what happens to messages from untrusted senders.
+    pub fn action(&self) -> UntrustedAction {
+        self.action
+    }
+
+    /// Returns true if the message was sent by a trusted sender.
+    pub(crate) fn is_trusted(&self, message: &DataMessageStanza) -> bool {
+        if self.sender_ids.contains(&message.from) {
+            return true;
+        }
+        let Some(key) = vapid_key(message).and_then(|key| decode_base64(key).ok()) else {
+            return false;
+        };
+        if !self.vapid_keys.contains(&key) {
+            return false;
+        }
+
+        // The key alone is public, only a signature proves the message came from its owner.
+        jwt(message).is_some_and(|jwt| verify_jwt(jwt, &key, sent_secs(message)).unwrap_or(false))
+    }
+}
+
+/// Returns the base64 encoded VAPID public key of the sender, if any.
+pub(crate) fn vapid_key(message: &DataMessageStanza) -> Option<&str> {
+    let authorization = message.app_data(app_data::AUTHORIZATION).ok();
+    authorization
+        .and_then(|data| header_param(strip_scheme(&data.value), "k"))
+        .or_else(|| {
+            let crypto_key = message.app_data(app_data::CRYPTO_KEY).ok()?;
+            header_param(&crypto_key.value, "p256ecdsa")
+        })
+}
+
+/// Returns the VAPID JWT of the `authorization` header, in either the `vapid t=..., k=...` or
+/// the `WebPush <jwt>` form.
+fn jwt(message: &DataMessageStanza) -> Option<&str> {
+    let value = &message.app_data(app_data::AUTHORIZATION).ok()?.value;
+    let (scheme, rest) = value.trim().split_once(' ')?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "vapid" => header_param(rest, "t"),
+        "webpush" => Some(rest.trim()),
+        _ => None,
+    }
+}
+
+/// Returns the time the message was sent in seconds since the unix epoch, or the current time if
+/// the server did not report it.
+fn sent_secs(message: &DataMessageStanza) -> Option<u64> {
+    match message.sent {
+        Some(sent_ms) => u64::try_from(sent_ms / 1000).ok(),
+        None => Some(SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs()),
+    }
+}
+
+fn strip_scheme(value: &str) -> &str {
+    value
+        .trim()
+        .split_once(' ')
+        .map_or(value, |(_, params)| params)
+}
+
+/// Verifies the ES256 signature, the audience and the expiry of a JWT sent at `sent` seconds
+/// since the unix epoch.
+fn verify_jwt(jwt: &str, public_key: &[u8], sent: Option<u64>) -> Option<bool> {
+    let (signed, signature) = jwt.rsplit_once('.')?;
+    let (_, claims) = signed.split_once('.')?;
+    let claims: serde_json::Value = serde_json::from_slice(&decode_base64(claims).ok()?).ok()?;
+    if claims.get("aud")?.as_str()?.trim_end_matches('/') != AUDIENCE {
+        return Some(false);
+    }
+    let sent = sent?;
+    let exp = claims.get("exp")?.as_u64()?;
+    if exp + CLOCK_SKEW_SECS < sent || exp > sent + MAX_EXPIRY_SECS + CLOCK_SKEW_SECS {
+        return Some(false);
+    }
+
+    let signature = decode_base64(signature).ok()?;
+    if signature.len() != SIGNATURE_LEN {
+        return Some(false);
+    }
+    let (r, s) = signature.split_at(SIGNATURE_LEN / 2);
+    let signature =
+        EcdsaSig::from_private_components(BigNum::from_slice(r).ok()?, BigNum::from_slice(s).ok()?)
+            .ok()?;
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).ok()?;
+    let mut context = BigNumContext::new().ok()?;
+    let point = EcPoint::from_bytes(&group, public_key, &mut context).ok()?;
+    let key = EcKey::from_public_key(&group, &point).ok()?;
+
+    signature.verify(&sha256(signed.as_bytes()), &key).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+    use openssl::ec::PointConversionForm;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mcs::AppData;
+
+    fn generate_key() -> EcKey<openssl::pkey::Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        EcKey::generate(&group).unwrap()
+    }
+
+    fn public_key(key: &EcKey<openssl::pkey::Private>) -> String {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut context = BigNumContext::new().unwrap();
+        let bytes = key
+            .public_key()
+            .to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut context)
+            .unwrap();
+        BASE64_URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn sign(key: &EcKey<openssl::pkey::Private>, claims: serde_json::Value) -> String {
+        let header = BASE64_URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = BASE64_URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signed = format!("{header}.{claims}");
+        let signature = EcdsaSig::sign(&sha256(signed.as_bytes()), key).unwrap();
+        let mut bytes = signature.r().to_vec_padded(32).unwrap();
+        bytes.extend(signature.s().to_vec_padded(32).unwrap());
+        format!("{signed}.{}", BASE64_URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn claims(aud: &str, expires_in: u64) -> serde_json::Value {
+        json!({
+            "aud": aud,
+            "exp": now() + expires_in,
+            "sub": "mailto:sender@example.com",
+        })
+    }
+
+    fn message(public_key: &str, jwt: Option<&str>) -> DataMessageStanza {
+        let mut app_data = vec![AppData {
+            key: app_data::CRYPTO_KEY.into(),
+            value: format!("dh=BAAA;p256ecdsa={public_key}"),
+        }];
+        if let Some(jwt) = jwt {
+            app_data.push(AppData {
+                key: app_data::AUTHORIZATION.into(),
+                value: format!("vapid t={jwt}, k={public_key}"),
+            });
+        }
+        DataMessageStanza {
+            from: "unknown".into(),
+            app_data,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_unsigned_message_with_trusted_key() {
+        let key = public_key(&generate_key());
+        let senders = TrustedSenders::new().with_vapid_key(&key).unwrap();
+
+        assert!(!senders.is_trusted(&message(&key, None)));
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let key = generate_key();
+        let public_key = public_key(&key);
+        let senders = TrustedSenders::new().with_vapid_key(&public_key).unwrap();
+        let jwt = sign(&key, claims(AUDIENCE, 60 * 60));
+
+        assert!(senders.is_trusted(&message(&public_key, Some(&jwt))));
+    }
+
+    #[test]
+    fn rejects_signature_of_other_key() {
+        let trusted = public_key(&generate_key());
+        let senders = TrustedSenders::new().with_vapid_key(&trusted).unwrap();
+        let jwt = sign(&generate_key(), claims(AUDIENCE, 60 * 60));
+
+        assert!(!senders.is_trusted(&message(&trusted, Some(&jwt))));
+    }
+
+    #[test]
+    fn rejects_wrong_audience_and_expiry() {
+        let key = generate_key();
+        let public_key = public_key(&key);
+        let senders = TrustedSenders::new().with_vapid_key(&public_key).unwrap();
+        let other_audience = sign(&key, claims("https://push.example.com", 60 * 60));
+        let too_long = sign(&key, claims(AUDIENCE, 2 * MAX_EXPIRY_SECS));
+        let mut expired = claims(AUDIENCE, 0);
+        expired["exp"] = json!(now() - 2 * CLOCK_SKEW_SECS);
+        let expired = sign(&key, expired);
+
+        for jwt in [other_audience, too_long, expired] {
+            assert!(!senders.is_trusted(&message(&public_key, Some(&jwt))));
+        }
+    }
+
+    #[test]
+    fn checks_expiry_against_sent_time() {
+        let key = generate_key();
+        let public_key = public_key(&key);
+        let senders = TrustedSenders::new().with_vapid_key(&public_key).unwrap();
+        let sent = now() - 2 * 60 * 60;
+        let mut claims = claims(AUDIENCE, 0);
+        claims["exp"] = json!(sent + 60 * 60);
+        let jwt = sign(&key, claims);
+
+        // Expired when received, but valid when the message was sent.
+        let mut delayed = message(&public_key, Some(&jwt));
+        delayed.sent = Some(sent as i64 * 1000);
+        assert!(senders.is_trusted(&delayed));
+
+        let mut late = message(&public_key, Some(&jwt));
+        late.sent = Some((sent + 2 * 60 * 60) as i64 * 1000);
+        assert!(!senders.is_trusted(&late));
+    }
+}
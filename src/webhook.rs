@@ -0,0 +1,582 @@
+//! Forwarding of notifications to an HTTP endpoint.
+//!
+//! Each notification is POSTed with its payload as the body and its metadata in `X-Fcm-*`
+//! headers, and only acknowledged to FCM once the endpoint responds with a 2xx status.
+//! Deliveries that keep failing are retried with exponential backoff and, if a queue directory
+//! is configured, persisted there until they succeed. Waiting for a delivery never stops the
+//! client from reading, so the connection to FCM stays alive while the webhook is slow.
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use futures_util::StreamExt as _;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::credentials::write_atomic;
+use crate::log;
+use crate::{AckHandle, Client, Notification, Secret};
+
+/// Header with the unix time in seconds the request was signed at.
+pub const TIMESTAMP_HEADER: &str = "x-fcm-timestamp";
+/// Header with `sha256=` followed by the hex encoded HMAC-SHA256 of the timestamp, the other
+/// `x-fcm-*` headers and the body:
+///
+/// ```text
+/// {timestamp}\n
+/// {name}:{value}\n  (for each other x-fcm-* header, sorted by name)
+/// \n
+/// {body}
+/// ```
+pub const SIGNATURE_HEADER: &str = "x-fcm-signature";
+/// Prefix of the headers covered by the signature.
+const METADATA_PREFIX: &str = "x-fcm-";
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const DEFAULT_CONCURRENCY: usize = 16;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Forwards notifications to a webhook.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    secret: Option<Arc<Secret<Vec<u8>>>>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    timeout: Duration,
+    queue: Option<PathBuf>,
+    /// Limits the deliveries in flight, including their retries.
+    permits: Arc<Semaphore>,
+    /// Acknowledgements of queued deliveries, by persistent ID.
+    pending: Arc<Mutex<HashMap<String, AckHandle>>>,
+    http: reqwest::Client,
+}
+
+impl Webhook {
+    /// Creates a forwarder POSTing to `url`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            secret: None,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            timeout: DEFAULT_TIMEOUT,
+            queue: None,
+            permits: Arc::new(Semaphore::new(DEFAULT_CONCURRENCY)),
+            pending: Default::default(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Signs requests with HMAC-SHA256, see [`SIGNATURE_HEADER`].
+    pub fn with_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.secret = Some(Arc::new(Secret::new(secret.into())));
+        self
+    }
+
+    /// Sets the number of attempts before a delivery is queued, and the backoff between them.
+    ///
+    /// The backoff doubles after each attempt, up to `max_backoff`.
+    pub fn with_retries(
+        mut self,
+        max_attempts: u32,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    ) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Sets the time after which a request is aborted and counted as a failed attempt, 30 seconds
+    /// by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of deliveries in flight at once, 16 by default.
+    ///
+    /// Further notifications are added to the queue if one is configured, and wait in memory
+    /// otherwise.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.permits = Arc::new(Semaphore::new(concurrency.max(1)));
+        self
+    }
+
+    /// Persists failed deliveries in `dir` and retries them every `max_backoff`, also after a
+    /// restart.
+    pub fn with_queue(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.queue = Some(dir.into());
+        self
+    }
+
+    /// Receives notifications with `client` and forwards them until the stream ends.
+    pub async fn run(self, client: Client) {
+        if let Some(queue) = &self.queue {
+            tokio::spawn(self.clone().retry_queue(queue.clone()));
+        }
+
+        let mut client = client.with_manual_ack();
+        let mut notifications = std::pin::pin!(client.notifications());
+        while let Some(notification) = notifications.next().await {
+            match notification {
+                Ok(notification) => self.dispatch(notification),
+                Err(error) => log::error!("{error}"),
+            }
+        }
+    }
+
+    /// Starts delivering a notification without waiting for deliveries in flight, so the client
+    /// keeps reading from FCM.
+    fn dispatch(&self, notification: Notification) {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            let webhook = self.clone();
+            tokio::spawn(async move {
+                webhook.forward(notification).await;
+                drop(permit);
+            });
+            return;
+        }
+
+        if let Some(queue) = &self.queue {
+            let delivery = Delivery::new(&notification);
+            if self.enqueue(queue, delivery, notification.ack.clone()) {
+                return;
+            }
+        }
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            let Ok(permit) = webhook.permits.clone().acquire_owned().await else {
+                return;
+            };
+            webhook.forward(notification).await;
+            drop(permit);
+        });
+    }
+
+    /// Delivers a notification, acknowledging it on success and queueing it otherwise.
+    async fn forward(self, notification: Notification) {
+        let delivery = Delivery::new(&notification);
+        let ack = notification.ack;
+        if self.is_queued(&delivery.persistent_id) {
+            // Redelivered by FCM while waiting in the queue.
+            if let Some(ack) = ack {
+                self.pending_acks().insert(delivery.persistent_id, ack);
+            }
+            return;
+        }
+
+        let mut backoff = self.initial_backoff;
+        for attempt in 1..=self.max_attempts {
+            match self.send(&delivery).await {
+                Ok(()) => {
+                    if let Some(ack) = ack {
+                        ack.ack();
+                    }
+                    return;
+                }
+                Err(error) => log::warn!(
+                    "webhook delivery of {} failed (attempt {attempt}/{}): {error}",
+                    delivery.persistent_id,
+                    self.max_attempts
+                ),
+            }
+            if attempt < self.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(self.max_backoff);
+            }
+        }
+
+        let Some(queue) = &self.queue else {
+            log::error!(
+                "dropping webhook delivery of {}, FCM redelivers it after a reconnect",
+                delivery.persistent_id
+            );
+            return;
+        };
+        self.enqueue(queue, delivery, ack);
+    }
+
+    /// Persists a delivery for [`Self::retry_queue()`], returning false if that failed.
+    fn enqueue(&self, queue: &Path, delivery: Delivery, ack: Option<AckHandle>) -> bool {
+        if let Err(error) = delivery.save(queue) {
+            log::error!("failed to queue {}: {error}", delivery.persistent_id);
+            return false;
+        }
+        if let Some(ack) = ack {
+            self.pending_acks().insert(delivery.persistent_id, ack);
+        }
+        true
+    }
+
+    /// Retries queued deliveries, oldest first, until one fails.
+    async fn retry_queue(self, queue: PathBuf) {
+        loop {
+            match Delivery::load_all(&queue) {
+                Ok(deliveries) => {
+                    for (path, delivery) in deliveries {
+                        if let Err(error) = self.send(&delivery).await {
+                            log::warn!("queued webhook delivery failed: {error}");
+                            break;
+                        }
+                        if let Err(error) = fs::remove_file(&path) {
+                            log::error!("failed to remove {}: {error}", path.display());
+                        }
+                        if let Some(ack) = self.pending_acks().remove(&delivery.persistent_id) {
+                            ack.ack();
+                        }
+                    }
+                }
+                Err(error) => log::error!("failed to read webhook queue: {error}"),
+            }
+            tokio::time::sleep(self.max_backoff).await;
+        }
+    }
+
+    async fn send(&self, delivery: &Delivery) -> Result<(), WebhookError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        for (name, value) in &delivery.headers {
+            match (
+                HeaderName::try_from(name),
+                HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => log::warn!("skipping invalid header `{name}`"),
+            }
+        }
+        if let Some(secret) = &self.secret {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .to_string();
+            let content = signed_content(&timestamp, &headers, &delivery.payload);
+            let signature = sign(secret.expose(), &content)?;
+            headers.insert(TIMESTAMP_HEADER, HeaderValue::from_str(&timestamp)?);
+            headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature)?);
+        }
+
+        let response = self
+            .http
+            .post(&self.url)
+            .timeout(self.timeout)
+            .headers(headers)
+            .body(delivery.payload.clone())
+            .send()
+            .await?;
+        // Redirects without a location are returned as is, they are not a delivery either.
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(WebhookError::Status(status)),
+        }
+    }
+
+    fn is_queued(&self, persistent_id: &str) -> bool {
+        self.queue
+            .as_deref()
+            .is_some_and(|queue| Delivery::path(queue, persistent_id).exists())
+    }
+
+    fn pending_acks(&self) -> std::sync::MutexGuard<'_, HashMap<String, AckHandle>> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Request for a notification, as stored in the queue.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Delivery {
+    persistent_id: String,
+    headers: Vec<(String, String)>,
+    #[serde(with = "base64_bytes")]
+    payload: Vec<u8>,
+}
+
+impl Delivery {
+    fn new(notification: &Notification) -> Self {
+        let mut headers = vec![
+            ("x-fcm-persistent-id", notification.persistent_id.clone()),
+            ("x-fcm-from", notification.from.clone()),
+            ("x-fcm-category", notification.category.clone()),
+            ("x-fcm-app-id", notification.app_id.clone()),
+            ("x-fcm-encrypted", notification.encrypted.to_string()),
+        ];
+        if let Some(sent) = notification.sent {
+            headers.push(("x-fcm-sent", sent.to_string()));
+        }
+        if let Some(ttl) = notification.ttl {
+            headers.push(("x-fcm-ttl", ttl.to_string()));
+        }
+        if !notification.data.is_empty() {
+            let data = serde_json::to_string(&notification.data).unwrap_or_default();
+            headers.push(("x-fcm-data", data));
+        }
+
+        Self {
+            persistent_id: notification.persistent_id.clone(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.into(), value))
+                .collect(),
+            payload: notification.payload.clone(),
+        }
+    }
+
+    /// Returns the queue file of a persistent ID, named by its hash as IDs contain characters
+    /// that are not portable in file names.
+    fn path(queue: &Path, persistent_id: &str) -> PathBuf {
+        queue.join(format!("{}.json", hex(&sha256(persistent_id.as_bytes()))))
+    }
+
+    fn save(&self, queue: &Path) -> Result<(), WebhookError> {
+        fs::create_dir_all(queue)?;
+        let path = Self::path(queue, &self.persistent_id);
+        Ok(write_atomic(&path, &serde_json::to_vec(self)?)?)
+    }
+
+    /// Loads the queued deliveries, oldest first.
+    fn load_all(queue: &Path) -> io::Result<Vec<(PathBuf, Self)>> {
+        let dir = match fs::read_dir(queue) {
+            Ok(dir) => dir,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+        let mut entries = Vec::new();
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let modified = fs::metadata(&path)?.modified()?;
+            entries.push((modified, path));
+        }
+        entries.sort();
+
+        let mut deliveries = Vec::new();
+        for (_, path) in entries {
+            match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(delivery) => deliveries.push((path, delivery)),
+                Err(error) => log::error!("skipping invalid {}: {error}", path.display()),
+            }
+        }
+        Ok(deliveries)
+    }
+}
+
+/// Returns the content covered by the signature, see [`SIGNATURE_HEADER`].
+fn signed_content(timestamp: &str, headers: &HeaderMap, body: &[u8]) -> Vec<u8> {
+    let mut metadata: Vec<_> = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with(METADATA_PREFIX))
+        .filter(|(name, _)| *name != TIMESTAMP_HEADER && *name != SIGNATURE_HEADER)
+        .collect();
+    metadata.sort_by_key(|(name, _)| name.as_str());
+
+    let mut content = format!("{timestamp}\n").into_bytes();
+    for (name, value) in metadata {
+        content.extend_from_slice(name.as_str().as_bytes());
+        content.push(b':');
+        content.extend_from_slice(value.as_bytes());
+        content.push(b'\n');
+    }
+    content.push(b'\n');
+    content.extend_from_slice(body);
+    content
+}
+
+/// Returns `sha256=` followed by the hex encoded HMAC-SHA256 of `content`.
+fn sign(secret: &[u8], content: &[u8]) -> Result<String, WebhookError> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(content)?;
+    Ok(format!("sha256={}", hex(&signer.sign_to_vec()?)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+mod base64_bytes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        BASE64_URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Errors of a single webhook delivery.
+#[derive(Debug, Error)]
+#[error("webhook delivery failed: {0}")]
+pub enum WebhookError {
+    Http(#[from] reqwest::Error),
+    Io(#[from] io::Error),
+    Json(#[from] serde_json::Error),
+    Crypto(#[from] openssl::error::ErrorStack),
+    InvalidHeader(#[from] reqwest::header::InvalidHeaderValue),
+    #[error("webhook responded with {0}")]
+    Status(reqwest::StatusCode),
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt as _;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::ack::Acks;
+    use crate::credentials::test_path;
+    use crate::DataMessageStanza;
+
+    fn notification() -> Notification {
+        let message = DataMessageStanza {
+            persistent_id: Some("0:1700000000000000%7031b2e6f9fd7ecd".into()),
+            from: "1234".into(),
+            category: "org.example.app".into(),
+            sent: Some(1_700_000_000_000),
+            raw_data: Some(b"payload".to_vec()),
+            ..Default::default()
+        };
+        Notification::new(message, None)
+    }
+
+    /// Serves HTTP requests with `status`, returning the URL.
+    async fn serve(status: u16) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                // Reads until the end of the headers, the body is short enough to arrive with them.
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(len) => request.extend_from_slice(&buf[..len]),
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 {status} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    /// Forwards a notification to a webhook responding with `status`, returning whether it was
+    /// acknowledged.
+    async fn forward_with_status(status: u16) -> bool {
+        let webhook =
+            Webhook::new(serve(status).await).with_retries(2, Duration::ZERO, Duration::ZERO);
+        let mut acks = Acks::new();
+        let mut notification = notification();
+        notification.ack = Some(acks.handle(notification.persistent_id.clone()));
+
+        webhook.forward(notification).await;
+
+        acks.recv().now_or_never().is_some()
+    }
+
+    #[tokio::test]
+    async fn acks_after_success() {
+        assert!(forward_with_status(204).await);
+    }
+
+    #[tokio::test]
+    async fn does_not_ack_after_failure() {
+        assert!(!forward_with_status(500).await);
+        assert!(!forward_with_status(302).await);
+    }
+
+    #[test]
+    fn signs_metadata_and_body() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        for (name, value) in Delivery::new(&notification()).headers {
+            headers.insert(
+                HeaderName::try_from(name).unwrap(),
+                HeaderValue::try_from(value).unwrap(),
+            );
+        }
+
+        let content = signed_content("1700000001", &headers, b"payload");
+
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "1700000001\n\
+             x-fcm-app-id:org.example.app\n\
+             x-fcm-category:org.example.app\n\
+             x-fcm-encrypted:false\n\
+             x-fcm-from:1234\n\
+             x-fcm-persistent-id:0:1700000000000000%7031b2e6f9fd7ecd\n\
+             x-fcm-sent:1700000000000\n\
+             \n\
+             payload"
+        );
+        assert_ne!(
+            sign(b"secret", b"content").unwrap(),
+            sign(b"other secret", b"content").unwrap()
+        );
+    }
+
+    #[test]
+    fn queue_round_trip() {
+        let queue = test_path("webhook-queue");
+        let delivery = Delivery::new(&notification());
+        delivery.save(&queue).unwrap();
+        let webhook = Webhook::new("http://localhost/").with_queue(&queue);
+        let queued = webhook.is_queued(&delivery.persistent_id);
+
+        let deliveries = Delivery::load_all(&queue);
+        fs::remove_dir_all(&queue).unwrap();
+
+        assert!(queued);
+        let deliveries = deliveries.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let (path, loaded) = &deliveries[0];
+        assert_eq!(path, &Delivery::path(&queue, &delivery.persistent_id));
+        assert_eq!(loaded.persistent_id, delivery.persistent_id);
+        assert_eq!(loaded.headers, delivery.headers);
+        assert_eq!(loaded.payload, b"payload");
+    }
+}
@@ -0,0 +1,142 @@
+//! Web push encryption helpers for senders.
+//!
+//! These produce [`DataMessageStanza`]s in the form FCM delivers them, which is useful for
+//! testing and for relaying locally produced notifications through the same code path.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::mcs::{AppData, DataMessageStanza};
+
+/// Category of messages delivered to the Chromium based receiver.
+pub(crate) const CATEGORY: &str = "org.chromium.linux";
+
+/// App-data keys of web push messages.
+pub(crate) mod app_data {
+    pub(crate) const CRYPTO_KEY: &str = "crypto-key";
+    pub(crate) const ENCRYPTION: &str = "encryption";
+    pub(crate) const CONTENT_ENCODING: &str = "content-encoding";
+    pub(crate) const AUTHORIZATION: &str = "authorization";
+    pub(crate) const SUBTYPE: &str = "subtype";
+}
+
+/// Web push content encoding, see RFC 8291 and draft-ietf-webpush-encryption-04.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    /// Legacy encoding, with the sender key and salt sent in separate headers.
+    AesGcm,
+    /// Standard encoding, with the sender key and salt included in the payload.
+    Aes128Gcm,
+}
+
+impl ContentEncoding {
+    /// Returns the name of the encoding used in the `content-encoding` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AesGcm => "aesgcm",
+            Self::Aes128Gcm => "aes128gcm",
+        }
+    }
+}
+
+/// Encrypts `payload` for a receiver and builds the [`DataMessageStanza`] FCM would deliver.
+///
+/// `public_key` and `auth_secret` are the base64 encoded values of the receiver's
+/// [`Keys`](crate::Keys). The stanza has a random persistent id and an empty `from`.
+pub fn encrypt(
+    public_key: &str,
+    auth_secret: &str,
+    payload: &[u8],
+    encoding: ContentEncoding,
+) -> Result<DataMessageStanza, EncryptError> {
+    let public_key = BASE64_URL_SAFE_NO_PAD.decode(public_key)?;
+    let auth_secret = BASE64_URL_SAFE_NO_PAD.decode(auth_secret)?;
+    let mut app_data = vec![AppData {
+        key: app_data::CONTENT_ENCODING.into(),
+        value: encoding.as_str().into(),
+    }];
+
+    let raw_data = match encoding {
+        ContentEncoding::AesGcm => {
+            let block = ece::legacy::encrypt_aesgcm(&public_key, &auth_secret, payload)?;
+            for (key, value) in block.headers(None) {
+                app_data.push(AppData {
+                    key: key.to_ascii_lowercase(),
+                    value,
+                });
+            }
+            BASE64_URL_SAFE_NO_PAD.decode(block.body())?
+        }
+        ContentEncoding::Aes128Gcm => ece::encrypt(&public_key, &auth_secret, payload)?,
+    };
+    let sent = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default();
+
+    Ok(DataMessageStanza {
+        id: Some(Uuid::new_v4().to_string()),
+        from: String::new(),
+        category: CATEGORY.into(),
+        app_data,
+        persistent_id: Some(format!("0:{}", Uuid::new_v4().simple())),
+        sent: Some(sent),
+        raw_data: Some(raw_data),
+        ..Default::default()
+    })
+}
+
+/// Returns the value of parameter `name` in a header such as `dh=...; p256ecdsa=...`.
+pub(crate) fn header_param<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split([';', ','])
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+}
+
+/// Decodes url-safe base64 with or without padding.
+pub(crate) fn decode_base64(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    BASE64_URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))
+}
+
+/// Errors returned by [`encrypt()`].
+#[derive(Debug, Error)]
+#[error("failed to encrypt message: {0}")]
+pub enum EncryptError {
+    Ece(#[from] ece::Error),
+    Base64Decode(#[from] base64::DecodeError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, Credentials};
+
+    fn round_trip(encoding: ContentEncoding) {
+        let credentials = Credentials::for_test();
+        let keys = &credentials.keys;
+        let message = encrypt(
+            &keys.public_key,
+            keys.auth_secret.expose(),
+            b"payload",
+            encoding,
+        )
+        .unwrap();
+        let client = Client::new(credentials).unwrap();
+
+        assert_eq!(client.decrypt(&message).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn aesgcm_round_trip() {
+        round_trip(ContentEncoding::AesGcm);
+    }
+
+    #[test]
+    fn aes128gcm_round_trip() {
+        round_trip(ContentEncoding::Aes128Gcm);
+    }
+}